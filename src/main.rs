@@ -1,6 +1,7 @@
 #![feature(proc_macro_hygiene, decl_macro)]
 #![crate_name = "elevate_backend"]
 
+extern crate base64;
 extern crate bcrypt;
 extern crate chrono;
 extern crate dotenv;
@@ -10,8 +11,12 @@ extern crate lettre;
 extern crate lettre_email;
 #[macro_use]
 extern crate log;
+extern crate openssl;
 extern crate postgres;
 extern crate postgres_types;
+extern crate r2d2;
+extern crate r2d2_postgres;
+extern crate rand;
 extern crate reqwest;
 #[macro_use]
 extern crate rocket;
@@ -21,14 +26,20 @@ extern crate rocket_cors;
 #[macro_use]
 extern crate rocket_include_static_resources;
 extern crate serde_json;
+extern crate sha2;
 extern crate urlencoding;
 
+mod config;
+mod migrations;
 mod models;
 mod services;
 mod views;
 
+use config::Config;
+use models::database::{PGConnection, PGPool};
 use rocket_cors::{catch_all_options_routes, Cors, CorsOptions};
 use rocket_include_static_resources::StaticResponse;
+use std::env;
 
 fn setup_logger() -> Result<(), fern::InitError> {
     fern::Dispatch::new()
@@ -52,11 +63,33 @@ fn setup_cors() -> Result<Cors, rocket_cors::Error> {
     CorsOptions::default().to_cors() // Using default cors. Restrict access later
 }
 
+fn run_migrations(pool: &PGPool) -> Result<(), String> {
+    let mut connection = PGConnection::from_pool(pool)?;
+    let applied = migrations::run(&mut connection)?;
+    info!("Applied {} database migration(s)", applied.len());
+    Ok(())
+}
+
 fn main() {
     dotenv::dotenv().ok();
     setup_logger().expect("Couldn't set up logger");
+    let rocket = rocket::ignite();
+    let config = Config::load(rocket.config()).expect("Invalid configuration");
+    let pool = PGConnection::init_pool(&config.database)
+        .expect("Couldn't create database connection pool");
+    run_migrations(&pool).expect("Couldn't migrate database");
+
+    // `elevate-backend migrate` only brings the schema up to date
+    if env::args().nth(1).as_deref() == Some("migrate") {
+        return;
+    }
+
+    services::outbox::start_workers(&config, &pool);
+    services::expiry::start_expiry_job(&config, &pool);
+    services::retention::start_pruning_job(&config, &pool);
+
     let cors = setup_cors().expect("Couldn't generate CORS");
-    rocket::ignite()
+    rocket
         .register(catchers![
             views::catchers::internal_error,
             views::catchers::not_found,
@@ -79,12 +112,29 @@ fn main() {
             "/users",
             routes![
                 views::user::login,
+                views::user::refresh_token,
+                views::user::logout,
+                views::user::logout_everywhere,
+                views::user::get_sessions,
+                views::user::revoke_session,
                 views::user::create_user,
                 views::user::verify_email,
                 views::user::send_verification_email,
                 views::user::request_password_reset,
                 views::user::reset_password,
                 views::user::update_device_token,
+                views::user::get_device_tokens,
+                views::user::delete_device_token,
+                views::user::get_blocked_users,
+                views::user::unblock_user,
+                views::user::get_preferences,
+                views::user::update_preferences,
+                views::user::get_privacy_settings,
+                views::user::update_privacy_settings,
+                views::zone::get_zones,
+                views::zone::create_zone,
+                views::zone::update_zone,
+                views::zone::delete_zone,
                 views::location::update_user_location,
                 views::location::get_user_location,
                 views::location::get_user_address
@@ -98,7 +148,11 @@ fn main() {
                 views::alert::update_alert,
                 views::alert::resolve_alert,
                 views::alert::delete_alert,
-                views::alert::get_by_viewport
+                views::alert::block_alert_creator,
+                views::alert::get_by_viewport,
+                views::alert::get_nearby,
+                views::alert::get_clusters,
+                views::alert::get_alert_track
             ],
         )
         .mount(
@@ -113,6 +167,8 @@ fn main() {
             ],
         )
         .mount("/", catch_all_options_routes())
+        .manage(config)
+        .manage(pool)
         .manage(cors.clone())
         .attach(cors)
         .attach(StaticResponse::fairing(|resources| {
@@ -10,4 +10,4 @@ create table if not exists users (
     constraint unique_email unique (email)
 );
 
-create index user_email_index on users (email);
\ No newline at end of file
+create index if not exists user_email_index on users (email);
\ No newline at end of file
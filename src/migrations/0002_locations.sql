@@ -7,7 +7,7 @@ create table if not exists locations (
     constraint single_location_per_user unique (user_id)
 );
 
-create index lat_long_index on locations (latitude, longitude);
+create index if not exists lat_long_index on locations (latitude, longitude);
 
 create or replace function calculate_distance(lat1 real, lon1 real, lat2 real, lon2 real)
 returns real AS $dist$
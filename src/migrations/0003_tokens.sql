@@ -14,4 +14,4 @@ create table if not exists firebase_device_tokens (
     constraint unique_token_device_per_user unique (user_id)
 );
 
-create index token_user_id_index on firebase_device_tokens (user_id);
\ No newline at end of file
+create index if not exists token_user_id_index on firebase_device_tokens (user_id);
\ No newline at end of file
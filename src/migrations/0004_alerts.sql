@@ -12,7 +12,8 @@ insert into alert_types (name, alert_level) values
 ('Assault', 1),
 ('Other Emergency', 1),
 ('Suspicious Person Spotted', 2),
-('Garage Sale', 3);
+('Garage Sale', 3)
+on conflict (name) do nothing;
 
 create table if not exists alerts (
     id bigserial primary key,
@@ -30,4 +31,4 @@ create table if not exists alerts (
     updated_at timestamp without time zone default now()
 );
 
-create index alert_location_index on alerts (is_resolved, latitude, longitude);
\ No newline at end of file
+create index if not exists alert_location_index on alerts (is_resolved, latitude, longitude);
\ No newline at end of file
@@ -0,0 +1,20 @@
+create table if not exists sessions (
+    id bigserial primary key,
+    user_id bigint not null references users (id) on delete cascade,
+    created_at timestamp without time zone default now(),
+    last_seen_at timestamp without time zone default now(),
+    revoked_at timestamp without time zone
+);
+
+create index if not exists session_user_id_index on sessions (user_id);
+
+-- Every refresh token belongs to a session, which acts as its rotation family
+create table if not exists refresh_tokens (
+    token_hash text primary key,
+    session_id bigint not null references sessions (id) on delete cascade,
+    expires_at timestamp without time zone not null,
+    used_at timestamp without time zone,
+    created_at timestamp without time zone default now()
+);
+
+create index if not exists refresh_token_session_index on refresh_tokens (session_id);
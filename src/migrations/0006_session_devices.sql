@@ -0,0 +1,10 @@
+alter table sessions
+    add column if not exists device_name text,
+    add column if not exists platform text,
+    add column if not exists ip_address text;
+
+-- Device tokens registered from a session stop receiving notifications once it is revoked
+alter table firebase_device_tokens
+    add column if not exists session_id bigint references sessions (id) on delete cascade;
+
+create index if not exists token_session_id_index on firebase_device_tokens (session_id);
@@ -0,0 +1,14 @@
+alter table firebase_device_tokens drop constraint if exists unique_token_device_per_user;
+
+alter table firebase_device_tokens
+    add column if not exists platform text,
+    add column if not exists app_version text;
+
+-- A device token identifies a single app install, keep only its latest registration
+delete from firebase_device_tokens older
+    using firebase_device_tokens newer
+where older.token = newer.token
+    and (coalesce(older.updated_at, '-infinity'), older.ctid)
+        < (coalesce(newer.updated_at, '-infinity'), newer.ctid);
+
+alter table firebase_device_tokens add constraint unique_device_token unique (token);
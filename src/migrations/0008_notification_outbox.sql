@@ -0,0 +1,20 @@
+-- Push notifications waiting to be delivered by the outbox workers.
+-- status is one of pending, sending, sent or dead
+create table if not exists notification_outbox (
+    id bigserial primary key,
+    alert_id bigint not null references alerts (id) on delete cascade,
+    user_id bigint not null references users (id) on delete cascade,
+    token text not null,
+    title text not null,
+    body text not null,
+    data jsonb not null default '{}',
+    status text not null default 'pending',
+    attempts integer not null default 0,
+    next_attempt_at timestamp without time zone not null default now(),
+    last_error text,
+    created_at timestamp without time zone default now(),
+    updated_at timestamp without time zone default now()
+);
+
+create index if not exists notification_outbox_due_index on notification_outbox (next_attempt_at)
+    where status in ('pending', 'sending');
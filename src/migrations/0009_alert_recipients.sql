@@ -0,0 +1,17 @@
+-- Users never notified about alerts from each other, in either direction
+create table if not exists blocked_users (
+    user_id bigint not null references users (id) on delete cascade,
+    blocked_user_id bigint not null references users (id) on delete cascade,
+    created_at timestamp without time zone default now(),
+    primary key (user_id, blocked_user_id)
+);
+
+create index if not exists blocked_user_id_index on blocked_users (blocked_user_id);
+
+-- The audience of each alert, so follow-ups reach the same users exactly once
+create table if not exists alert_recipients (
+    alert_id bigint not null references alerts (id) on delete cascade,
+    user_id bigint not null references users (id) on delete cascade,
+    notified_at timestamp without time zone default now(),
+    primary key (alert_id, user_id)
+);
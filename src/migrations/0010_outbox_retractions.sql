@@ -0,0 +1,7 @@
+-- Retractions are queued just before their alert is deleted, so outbox rows must outlive it
+alter table notification_outbox alter column alert_id drop not null;
+
+alter table notification_outbox drop constraint if exists notification_outbox_alert_id_fkey;
+
+alter table notification_outbox add constraint notification_outbox_alert_id_fkey
+    foreign key (alert_id) references alerts (id) on delete set null;
@@ -0,0 +1,13 @@
+-- Haversine distance in miles, in double precision so short distances don't round to zero
+create or replace function great_circle_distance(lat1 float8, lon1 float8, lat2 float8, lon2 float8)
+returns float8 as $dist$
+    select 2 * 3958.8 * asin(least(1, sqrt(
+        power(sin(radians(lat2 - lat1) / 2), 2)
+        + cos(radians(lat1)) * cos(radians(lat2)) * power(sin(radians(lon2 - lon1) / 2), 2)
+    )));
+$dist$ language sql immutable;
+
+create or replace function calculate_distance(lat1 real, lon1 real, lat2 real, lon2 real)
+returns real as $dist$
+    select great_circle_distance(lat1, lon1, lat2, lon2)::real;
+$dist$ language sql immutable;
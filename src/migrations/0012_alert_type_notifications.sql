@@ -0,0 +1,23 @@
+-- How loudly and how far each alert type notifies. push_priority is high or normal
+alter table alert_types
+    add column if not exists notify bool not null default true,
+    add column if not exists notification_radius_miles real not null default 10,
+    add column if not exists push_priority text not null default 'high',
+    add column if not exists sound text default 'default',
+    add column if not exists android_channel text;
+
+alter table alert_types drop constraint if exists alert_type_push_priority;
+alter table alert_types add constraint alert_type_push_priority
+    check (push_priority in ('high', 'normal'));
+
+update alert_types set
+    notification_radius_miles = case alert_level when 1 then 10 when 2 then 5 else 2 end,
+    push_priority = case when alert_level <= 2 then 'high' else 'normal' end,
+    sound = case when alert_level <= 2 then 'default' end,
+    android_channel = case alert_level when 1 then 'emergency' when 2 then 'warning' else 'community' end;
+
+-- Delivery settings are copied from the alert type when a notification is queued
+alter table notification_outbox
+    add column if not exists push_priority text not null default 'high',
+    add column if not exists sound text,
+    add column if not exists android_channel text;
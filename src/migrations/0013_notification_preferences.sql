@@ -0,0 +1,31 @@
+-- Per user filters applied when an alert fans out. radius_miles can only narrow the radius of
+-- the alert type, users only hear about alerts at or above max_alert_level (1 is the most
+-- severe) and quiet hours are in the user's own timezone
+create table if not exists notification_preferences (
+    user_id bigint primary key references users (id) on delete cascade,
+    radius_miles real,
+    max_alert_level smallint not null default 3,
+    muted_alert_types text[] not null default '{}',
+    quiet_hours_start time,
+    quiet_hours_end time,
+    timezone text not null default 'UTC',
+    emergency_override bool not null default true,
+    distance_unit text not null default 'mi',
+    created_at timestamp without time zone default now(),
+    updated_at timestamp without time zone default now(),
+    constraint preference_distance_unit check (distance_unit in ('mi', 'km'))
+);
+
+-- Quiet hours may wrap around midnight, e.g. 22:00 to 07:00
+create or replace function in_quiet_hours(quiet_start time, quiet_end time, tz text)
+returns bool as $quiet$
+    select case
+        when quiet_start is null or quiet_end is null then false
+        when quiet_start <= quiet_end then
+            (now() at time zone tz)::time >= quiet_start
+            and (now() at time zone tz)::time < quiet_end
+        else
+            (now() at time zone tz)::time >= quiet_start
+            or (now() at time zone tz)::time < quiet_end
+    end;
+$quiet$ language sql stable;
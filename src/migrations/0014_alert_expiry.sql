@@ -0,0 +1,30 @@
+-- How long alerts of each type stay active when the creator gives no expiry, null never expires
+alter table alert_types add column if not exists default_ttl_minutes integer;
+
+alter table alert_types drop constraint if exists alert_type_default_ttl;
+alter table alert_types add constraint alert_type_default_ttl
+    check (default_ttl_minutes is null or default_ttl_minutes > 0);
+
+update alert_types set
+    default_ttl_minutes = case alert_level when 1 then 6 * 60 when 2 then 2 * 60 else 24 * 60 end;
+
+-- resolved_by is creator or system, the latter for alerts that expired
+alter table alerts
+    add column if not exists expires_at timestamp without time zone,
+    add column if not exists resolved_at timestamp without time zone,
+    add column if not exists resolved_by text;
+
+alter table alerts drop constraint if exists alert_resolved_by;
+alter table alerts add constraint alert_resolved_by
+    check (resolved_by is null or resolved_by in ('creator', 'system'));
+
+-- Alerts created before expiry existed get their type's lifetime starting now, rather than from
+-- when they were created, so the first sweep doesn't resolve the whole backlog at once
+update alerts a set expires_at = now() + make_interval(mins => t.default_ttl_minutes)
+from alert_types t
+where a.alert_type = t.name
+    and a.expires_at is null
+    and not a.is_resolved
+    and t.default_ttl_minutes is not null;
+
+create index if not exists alert_expiry_index on alerts (expires_at) where not is_resolved;
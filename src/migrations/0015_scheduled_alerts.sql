@@ -0,0 +1,26 @@
+-- Planned events, like garage sales, take place between starts_at and ends_at. notified_at is
+-- set once the deferred notifications of a scheduled alert went out
+alter table alerts
+    add column if not exists starts_at timestamp without time zone,
+    add column if not exists ends_at timestamp without time zone,
+    add column if not exists notified_at timestamp without time zone;
+
+alter table alerts drop constraint if exists alert_schedule;
+alter table alerts add constraint alert_schedule
+    check (starts_at is null or ends_at is null or ends_at > starts_at);
+
+create index if not exists alert_schedule_index on alerts (starts_at)
+    where notified_at is null and not is_resolved;
+
+-- upcoming, active or past. Ended events have their end as expiry, so only that is checked
+create or replace function alert_status(
+    is_resolved bool,
+    starts_at timestamp without time zone,
+    expires_at timestamp without time zone
+) returns text as $$
+    select case
+        when is_resolved or expires_at <= now() then 'past'
+        when starts_at > now() then 'upcoming'
+        else 'active'
+    end
+$$ language sql stable;
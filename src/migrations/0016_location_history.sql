@@ -0,0 +1,27 @@
+-- Every point the app uploads, timed by the device clock. accuracy is in meters, speed in
+-- meters per second and heading in degrees from north
+create table if not exists location_history (
+    id bigserial primary key,
+    user_id bigint not null references users (id) on delete cascade,
+    latitude real not null,
+    longitude real not null,
+    recorded_at timestamp without time zone not null,
+    accuracy real,
+    speed real,
+    heading real,
+    source text,
+    created_at timestamp without time zone default now(),
+    constraint unique_location_point unique (user_id, recorded_at)
+);
+
+create index if not exists location_history_recorded_at_index on location_history (recorded_at);
+
+-- The latest point of each user, which older points uploaded out of order never overwrite
+alter table locations
+    add column if not exists recorded_at timestamp without time zone,
+    add column if not exists accuracy real,
+    add column if not exists speed real,
+    add column if not exists heading real,
+    add column if not exists source text;
+
+update locations set recorded_at = updated_at where recorded_at is null;
@@ -0,0 +1,17 @@
+-- Every position of alerts that follow their creator, timed by the device clock
+create table if not exists alert_track_points (
+    id bigserial primary key,
+    alert_id bigint not null references alerts (id) on delete cascade,
+    latitude real not null,
+    longitude real not null,
+    accuracy real,
+    recorded_at timestamp without time zone not null,
+    constraint unique_alert_track_point unique (alert_id, recorded_at)
+);
+
+-- Tracked alerts start their trail where they are now
+insert into alert_track_points (alert_id, latitude, longitude, recorded_at)
+select id, latitude, longitude, coalesce(updated_at, now())
+from alerts
+where track_location
+on conflict (alert_id, recorded_at) do nothing;
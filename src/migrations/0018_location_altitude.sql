@@ -0,0 +1,3 @@
+-- Altitude in meters above the WGS 84 ellipsoid, as reported by the device
+alter table location_history add column if not exists altitude real;
+alter table locations add column if not exists altitude real;
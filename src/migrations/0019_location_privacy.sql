@@ -0,0 +1,15 @@
+-- Approximate locations are snapped to the privacy grid everywhere but in alert targeting
+create table if not exists privacy_settings (
+    user_id bigint primary key references users (id) on delete cascade,
+    location_precision text not null default 'exact',
+    created_at timestamp without time zone default now(),
+    updated_at timestamp without time zone default now(),
+    constraint privacy_location_precision check (location_precision in ('exact', 'approximate'))
+);
+
+-- Alerts can be posted with their location rounded to the block or the neighbourhood
+alter table alerts add column if not exists location_precision text not null default 'exact';
+
+alter table alerts drop constraint if exists alert_location_precision;
+alter table alerts add constraint alert_location_precision
+    check (location_precision in ('exact', 'block', 'neighbourhood'));
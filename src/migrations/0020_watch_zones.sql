@@ -0,0 +1,43 @@
+-- Places users want alerts around besides where they are. Circles have a center and a radius
+-- in miles, polygons their [latitude, longitude] corners and the same shape as a geometric
+-- polygon of (longitude, latitude) points for containment checks. The bounding box prefilters
+-- zones before the exact check
+create table if not exists watch_zones (
+    id bigserial primary key,
+    user_id bigint not null references users (id) on delete cascade,
+    name text not null,
+    kind text not null,
+    latitude float8,
+    longitude float8,
+    radius_miles float8,
+    polygon jsonb,
+    boundary polygon,
+    min_latitude float8 not null,
+    max_latitude float8 not null,
+    min_longitude float8 not null,
+    max_longitude float8 not null,
+    notify_transitions bool not null default false,
+    created_at timestamp without time zone default now(),
+    updated_at timestamp without time zone default now(),
+    constraint unique_watch_zone_name unique (user_id, name),
+    constraint watch_zone_kind check (
+        (kind = 'circle' and latitude is not null and longitude is not null and radius_miles > 0)
+        or (kind = 'polygon' and polygon is not null and boundary is not null)
+    )
+);
+
+create index if not exists watch_zone_bounds_index on watch_zones (min_latitude, max_latitude);
+
+create or replace function zone_contains(
+    zone_latitude float8,
+    zone_longitude float8,
+    radius_miles float8,
+    boundary polygon,
+    latitude float8,
+    longitude float8
+) returns bool as $$
+    select case
+        when boundary is not null then boundary @> point(longitude, latitude)
+        else great_circle_distance(zone_latitude, zone_longitude, latitude, longitude) <= radius_miles
+    end
+$$ language sql immutable;
@@ -0,0 +1,16 @@
+-- The latest point accurate enough for alert targeting, which less accurate points never
+-- overwrite
+alter table locations
+    add column if not exists targeting_latitude real,
+    add column if not exists targeting_longitude real,
+    add column if not exists targeting_accuracy real;
+
+update locations
+set
+    targeting_latitude = latitude,
+    targeting_longitude = longitude,
+    targeting_accuracy = accuracy
+where targeting_latitude is null;
+
+create index if not exists targeting_lat_long_index
+    on locations (targeting_latitude, targeting_longitude);
@@ -0,0 +1,268 @@
+use postgres::Client;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+impl Migration {
+    pub fn checksum(&self) -> String {
+        format!("{:x}", Sha256::digest(self.sql.as_bytes()))
+    }
+}
+
+macro_rules! migration {
+    ($version:expr, $name:expr) => {
+        Migration {
+            version: $version,
+            name: $name,
+            sql: include_str!(concat!($name, ".sql")),
+        }
+    };
+}
+
+/// Every migration shipped with the binary, in the order they are applied.
+///
+/// To change the schema, add a new `NNNN_description.sql` file next to this one and append it
+/// here with the next version number. Never edit a migration that has already been released,
+/// the server refuses to start when an applied migration's checksum no longer matches.
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_users"),
+    migration!(2, "0002_locations"),
+    migration!(3, "0003_tokens"),
+    migration!(4, "0004_alerts"),
+    migration!(5, "0005_sessions"),
+    migration!(6, "0006_session_devices"),
+    migration!(7, "0007_device_tokens"),
+    migration!(8, "0008_notification_outbox"),
+    migration!(9, "0009_alert_recipients"),
+    migration!(10, "0010_outbox_retractions"),
+    migration!(11, "0011_great_circle_distance"),
+    migration!(12, "0012_alert_type_notifications"),
+    migration!(13, "0013_notification_preferences"),
+    migration!(14, "0014_alert_expiry"),
+    migration!(15, "0015_scheduled_alerts"),
+    migration!(16, "0016_location_history"),
+    migration!(17, "0017_alert_track_points"),
+    migration!(18, "0018_location_altitude"),
+    migration!(19, "0019_location_privacy"),
+    migration!(20, "0020_watch_zones"),
+    migration!(21, "0021_location_targeting"),
+];
+
+// Arbitrary key so that only one server instance migrates the database at a time
+const MIGRATION_LOCK_ID: i64 = 7_305_118_253;
+
+/// Applies all pending migrations in a single transaction, returning the versions applied.
+pub fn run(client: &mut Client) -> Result<Vec<i32>, String> {
+    let mut transaction = match client.transaction() {
+        Ok(transaction) => transaction,
+        Err(err) => {
+            error!("{}", err);
+            return Err(String::from("Could not start migration transaction"));
+        }
+    };
+
+    if let Err(err) = transaction.batch_execute(
+        format!(
+            "select pg_advisory_xact_lock({});
+            create table if not exists schema_history (
+                version integer primary key,
+                name text not null,
+                checksum text not null,
+                applied_at timestamp without time zone default now()
+            );
+            ",
+            MIGRATION_LOCK_ID
+        )
+        .as_str(),
+    ) {
+        error!("{}", err);
+        return Err(String::from("Could not create schema history table"));
+    }
+
+    let applied = match transaction.query(
+        "select version, name, checksum from schema_history order by version
+        ",
+        &[],
+    ) {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| {
+                (
+                    row.get::<_, i32>("version"),
+                    row.get::<_, String>("checksum"),
+                )
+            })
+            .collect::<BTreeMap<i32, String>>(),
+        Err(err) => {
+            error!("{}", err);
+            return Err(String::from("Could not read schema history"));
+        }
+    };
+
+    let mut problems = Vec::new();
+
+    for (version, checksum) in &applied {
+        match MIGRATIONS
+            .iter()
+            .find(|migration| migration.version == *version)
+        {
+            Some(migration) if migration.checksum() != *checksum => problems.push(format!(
+                "migration {} ({}) has changed since it was applied",
+                version, migration.name
+            )),
+            Some(_) => (),
+            None => problems.push(format!(
+                "migration {} was applied but is unknown to this build",
+                version
+            )),
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(format!(
+            "Refusing to migrate database: {}",
+            problems.join("; ")
+        ));
+    }
+
+    let mut newly_applied = Vec::new();
+
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.contains_key(&migration.version))
+    {
+        info!(
+            "Applying migration {} ({})",
+            migration.version, migration.name
+        );
+
+        if let Err(err) = transaction.batch_execute(migration.sql) {
+            error!("{}", err);
+            return Err(format!(
+                "Migration {} ({}) failed: {}",
+                migration.version, migration.name, err
+            ));
+        }
+
+        if let Err(err) = transaction.execute(
+            "insert into schema_history (
+                version,
+                name,
+                checksum
+            ) values ($1, $2, $3)
+            ",
+            &[&migration.version, &migration.name, &migration.checksum()],
+        ) {
+            error!("{}", err);
+            return Err(format!(
+                "Could not record migration {} in schema history",
+                migration.version
+            ));
+        }
+
+        newly_applied.push(migration.version);
+    }
+
+    match transaction.commit() {
+        Ok(_) => Ok(newly_applied),
+        Err(err) => {
+            error!("{}", err);
+            Err(String::from("Could not commit migrations"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::database::connect_test_database;
+
+    // Client whose tables all live in an empty schema of their own, since migrations commit
+    fn empty_schema(schema: &str) -> Client {
+        let mut client = connect_test_database();
+        client
+            .batch_execute(
+                format!(
+                    "drop schema if exists {schema} cascade;
+                    create schema {schema};
+                    set search_path to {schema};
+                    ",
+                    schema = schema
+                )
+                .as_str(),
+            )
+            .unwrap();
+        client
+    }
+
+    fn drop_schema(mut client: Client, schema: &str) {
+        client
+            .batch_execute(format!("drop schema {} cascade", schema).as_str())
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn migrating_twice_applies_nothing_the_second_time() {
+        let mut client = empty_schema("migrations_twice");
+
+        let applied = run(&mut client).unwrap();
+        assert_eq!(
+            applied,
+            MIGRATIONS
+                .iter()
+                .map(|migration| migration.version)
+                .collect::<Vec<i32>>()
+        );
+        assert_eq!(run(&mut client).unwrap(), Vec::<i32>::new());
+
+        drop_schema(client, "migrations_twice");
+    }
+
+    #[test]
+    #[ignore]
+    fn changed_migrations_are_refused() {
+        let mut client = empty_schema("migrations_changed");
+        run(&mut client).unwrap();
+        client
+            .batch_execute("update schema_history set checksum = 'tampered' where version = 2")
+            .unwrap();
+
+        let err = run(&mut client).unwrap_err();
+        assert!(
+            err.contains("migration 2 (0002_locations) has changed since it was applied"),
+            "{}",
+            err
+        );
+
+        drop_schema(client, "migrations_changed");
+    }
+
+    #[test]
+    #[ignore]
+    fn unknown_migrations_are_refused() {
+        let mut client = empty_schema("migrations_unknown");
+        run(&mut client).unwrap();
+        client
+            .batch_execute(
+                "insert into schema_history (version, name, checksum)
+                values (9999, '9999_from_the_future', 'unknown')
+                ",
+            )
+            .unwrap();
+
+        let err = run(&mut client).unwrap_err();
+        assert!(
+            err.contains("migration 9999 was applied but is unknown to this build"),
+            "{}",
+            err
+        );
+
+        drop_schema(client, "migrations_unknown");
+    }
+}
@@ -1,17 +1,47 @@
+use crate::config::{AlertConfig, Config, LocationConfig};
+use crate::models::error::ApiError;
+use crate::models::geo::{snap_to_grid, BoundingBox, LocationPrecision};
+use crate::models::privacy::PrivacySettings;
+use crate::models::track::TrackPoint;
 use crate::models::user::User;
 use crate::services::mapquest::{get_address, MapquestResult};
 use crate::{models::location::Location, services::mapquest::get_location};
-use chrono::NaiveDateTime;
-use postgres::Transaction;
+use chrono::{NaiveDateTime, Utc};
+use postgres::{Row, Transaction};
 use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Serialize, Deserialize)]
+// Alert types only change through migrations, so they are kept in memory between requests
+const ALERT_TYPE_CACHE_TTL: Duration = Duration::from_secs(300);
+static ALERT_TYPE_CACHE: RwLock<Option<(Instant, Vec<AlertType>)>> = RwLock::new(None);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AlertType {
     pub name: String,
 
     #[serde(rename = "alertLevel")]
     pub alert_level: i16,
 
+    // Whether alerts of this type push notifications at all
+    pub notify: bool,
+
+    #[serde(rename = "notificationRadius")]
+    pub notification_radius_miles: f32,
+
+    // Either high or normal
+    #[serde(rename = "pushPriority")]
+    pub push_priority: String,
+
+    pub sound: Option<String>,
+
+    #[serde(rename = "androidChannel")]
+    pub android_channel: Option<String>,
+
+    // Lifetime of alerts created without an expiry, none means they never expire
+    #[serde(rename = "defaultTtlMinutes")]
+    pub default_ttl_minutes: Option<i32>,
+
     #[serde(rename = "createdAt")]
     #[serde(skip_deserializing)]
     pub created_at: Option<NaiveDateTime>,
@@ -27,6 +57,12 @@ macro_rules! alert_type {
         AlertType {
             name: $row.get("name"),
             alert_level: $row.get("alert_level"),
+            notify: $row.get("notify"),
+            notification_radius_miles: $row.get("notification_radius_miles"),
+            push_priority: $row.get("push_priority"),
+            sound: $row.get("sound"),
+            android_channel: $row.get("android_channel"),
+            default_ttl_minutes: $row.get("default_ttl_minutes"),
             created_at: $row.get("created_at"),
             updated_at: $row.get("updated_at"),
         }
@@ -34,16 +70,33 @@ macro_rules! alert_type {
 }
 
 impl AlertType {
+    /// All alert types, served from memory unless the cache has expired.
     pub fn get_all(transaction: &mut Transaction) -> Vec<Self> {
+        if let Ok(cache) = ALERT_TYPE_CACHE.read() {
+            if let Some((loaded_at, alert_types)) = cache.as_ref() {
+                if loaded_at.elapsed() < ALERT_TYPE_CACHE_TTL {
+                    return alert_types.clone();
+                }
+            }
+        }
+
         match transaction.query(
-            "select * from alert_types
+            "select * from alert_types order by alert_level, name
             ",
             &[],
         ) {
-            Ok(rows) => rows
-                .iter()
-                .map(|row| alert_type!(row))
-                .collect::<Vec<AlertType>>(),
+            Ok(rows) => {
+                let alert_types = rows
+                    .iter()
+                    .map(|row| alert_type!(row))
+                    .collect::<Vec<AlertType>>();
+
+                if let Ok(mut cache) = ALERT_TYPE_CACHE.write() {
+                    *cache = Some((Instant::now(), alert_types.clone()));
+                }
+
+                alert_types
+            }
             Err(err) => {
                 error!("{}", err);
                 Vec::new()
@@ -51,21 +104,15 @@ impl AlertType {
         }
     }
 
-    pub fn get_by_name(name: &String, transaction: &mut Transaction) -> Option<Self> {
-        match transaction.query_one(
-            "select * from alert_types where name = $1
-            ",
-            &[name],
-        ) {
-            Ok(row) => Some(alert_type!(row)),
-            Err(err) => {
-                error!("{}", err);
-                None
-            }
-        }
+    pub fn get_by_name(name: &str, transaction: &mut Transaction) -> Option<Self> {
+        Self::get_all(transaction)
+            .into_iter()
+            .find(|alert_type| alert_type.name == name)
     }
 }
 
+/// The creator's contact details as far as they chose to display them. Deliberately holds
+/// nothing else, in particular never where the creator is.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AlertUserInfo {
     pub name: String,
@@ -125,6 +172,11 @@ pub struct Alert {
     #[serde(rename = "trackLocation")]
     pub track_location: bool,
 
+    // exact, block or neighbourhood, rounded alerts never store the exact location
+    #[serde(rename = "locationPrecision")]
+    #[serde(default = "exact_location_precision")]
+    pub location_precision: String,
+
     #[serde(skip)]
     pub created_by: String,
 
@@ -137,6 +189,32 @@ pub struct Alert {
     #[serde(skip_deserializing)]
     pub is_resolved: bool,
 
+    // Planned events notify shortly before they start instead of right away
+    #[serde(rename = "startsAt")]
+    pub starts_at: Option<NaiveDateTime>,
+
+    // Planned events expire when they end
+    #[serde(rename = "endsAt")]
+    pub ends_at: Option<NaiveDateTime>,
+
+    // Defaults to the alert type's lifetime, expired alerts are resolved by the system
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<NaiveDateTime>,
+
+    #[serde(rename = "resolvedAt")]
+    #[serde(skip_deserializing)]
+    pub resolved_at: Option<NaiveDateTime>,
+
+    // Either creator or system
+    #[serde(rename = "resolvedBy")]
+    #[serde(skip_deserializing)]
+    pub resolved_by: Option<String>,
+
+    // Distance from the searched point, only set by radius searches
+    #[serde(skip_deserializing)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance: Option<f64>,
+
     #[serde(rename = "createdAt")]
     #[serde(skip_deserializing)]
     pub created_at: Option<NaiveDateTime>,
@@ -160,38 +238,217 @@ macro_rules! alert {
             display_email: $row.get("display_email"),
             display_phone: $row.get("display_phone"),
             track_location: $row.get("track_location"),
+            location_precision: $row.get("location_precision"),
             created_by: $row.get("created_by"),
             user_info: Option::None,
             is_resolved: $row.get("is_resolved"),
+            starts_at: $row.get("starts_at"),
+            ends_at: $row.get("ends_at"),
+            expires_at: $row.get("expires_at"),
+            resolved_at: $row.get("resolved_at"),
+            resolved_by: $row.get("resolved_by"),
+            distance: Option::None,
             created_at: $row.get("created_at"),
             updated_at: $row.get("updated_at"),
         }
     };
 }
 
+fn exact_location_precision() -> String {
+    String::from("exact")
+}
+
+// Addresses read street, city, state, country. Blocks lose the house number, neighbourhoods
+// the whole street
+// Stands in for the place when there is no address for the coordinates
+fn coordinate_place(latitude: f32, longitude: f32) -> String {
+    format!("{:.5}, {:.5}", latitude, longitude)
+}
+
+fn round_place(place: &str, precision: LocationPrecision) -> String {
+    let mut parts = place
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>();
+
+    if precision == LocationPrecision::Neighbourhood && parts.len() > 1 {
+        parts.remove(0);
+    } else if precision != LocationPrecision::Exact {
+        if let Some(street) = parts.first_mut() {
+            if let Some((number, rest)) = street.split_once(' ') {
+                if number.chars().any(|c| c.is_ascii_digit()) {
+                    *street = rest.trim_start();
+                }
+            }
+        }
+    }
+
+    parts.join(", ")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlertSort {
+    Newest,
+    Oldest,
+}
+
+/// Where an alert is in its lifetime, planned events are upcoming until they start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlertStatus {
+    Upcoming,
+    Active,
+    Past,
+}
+
+impl AlertStatus {
+    // As returned by the alert_status database function
+    fn name(self) -> &'static str {
+        match self {
+            AlertStatus::Upcoming => "upcoming",
+            AlertStatus::Active => "active",
+            AlertStatus::Past => "past",
+        }
+    }
+}
+
+/// Position in an alert feed, pointing at the last alert of the previous page.
+#[derive(Debug, Clone, Copy)]
+pub struct AlertCursor {
+    pub created_at: NaiveDateTime,
+    pub id: i64,
+}
+
+const CURSOR_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+impl AlertCursor {
+    pub fn encode(&self) -> String {
+        base64::encode_config(
+            format!("{}_{}", self.created_at.format(CURSOR_TIME_FORMAT), self.id),
+            base64::URL_SAFE_NO_PAD,
+        )
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let decoded = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (created_at, id) = decoded.split_at(decoded.rfind('_')?);
+
+        Some(AlertCursor {
+            created_at: NaiveDateTime::parse_from_str(created_at, CURSOR_TIME_FORMAT).ok()?,
+            id: id[1..].parse().ok()?,
+        })
+    }
+}
+
+/// Address a tracked alert is shown at once it follows its creator to the point. Looked up
+/// before the location upload's transaction, geocoding is too slow to hold it open for.
+#[derive(Debug, Clone)]
+pub struct TrackingPlace {
+    pub latitude: f32,
+    pub longitude: f32,
+    pub place: String,
+}
+
+impl TrackingPlace {
+    pub fn lookup(location: &Location, config: &Config) -> Option<Self> {
+        match get_address(location.latitude, location.longitude, config) {
+            MapquestResult::Success(place) => Some(TrackingPlace {
+                latitude: location.latitude,
+                longitude: location.longitude,
+                place,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Filters for the alert feed. Unset filters match every alert.
+#[derive(Debug)]
+pub struct AlertFilters {
+    pub resolved: Option<bool>,
+    pub status: Option<AlertStatus>,
+    pub alert_types: Option<Vec<String>>,
+    // Only alerts at least this severe, 1 is the most severe level
+    pub min_level: Option<i16>,
+    pub created_after: Option<NaiveDateTime>,
+    pub created_before: Option<NaiveDateTime>,
+    // Email of the creator, only matches alerts that display it unless it's the requester's own
+    pub creator: Option<String>,
+    pub sort: AlertSort,
+    pub cursor: Option<AlertCursor>,
+    pub limit: i64,
+}
+
+/// Unresolved alerts grouped into one cell of the map grid.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AlertCluster {
+    // Centroid of the alerts in the cluster
+    pub latitude: f64,
+
+    pub longitude: f64,
+
+    pub count: i64,
+
+    // Most severe level among the alerts, 1 is the most severe
+    #[serde(rename = "highestLevel")]
+    pub highest_level: i16,
+
+    // Only listed for clusters too small to be shown as one
+    #[serde(skip)]
+    pub alert_ids: Vec<i64>,
+}
+
+#[macro_export]
+macro_rules! alert_cluster {
+    ($row:expr) => {
+        AlertCluster {
+            latitude: $row.get("latitude"),
+            longitude: $row.get("longitude"),
+            count: $row.get("count"),
+            highest_level: $row.get("highest_level"),
+            alert_ids: $row
+                .get::<_, Option<Vec<i64>>>("alert_ids")
+                .unwrap_or_default(),
+        }
+    };
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AlertNotificationInfo {
-    pub distance: f32,
+    pub user_id: i64,
+    // Only set when the user's own location put them in range
+    pub distance: Option<f32>,
+    // One of the user's watch zones containing the alert
+    pub zone: Option<String>,
     pub token: String,
+    // Unit the user prefers distances in, mi or km
+    pub distance_unit: String,
 }
 
 #[macro_export]
 macro_rules! alert_notification_info {
     ($row:expr) => {
         AlertNotificationInfo {
+            user_id: $row.get("user_id"),
             distance: $row.get("distance"),
+            zone: $row.get("zone"),
             token: $row.get("token"),
+            distance_unit: $row.get("distance_unit"),
         }
     };
 }
 
-pub const LAT_LNG_VIEW_PORT: f32 = 0.145; // 0.145 degrees ~ 10 miles
-
 impl Alert {
-    pub fn init(&mut self, transaction: &mut Transaction) -> Result<Self, String> {
-        if let Err(err) = self.fill_missing_info() {
-            return Err(err);
-        }
+    pub fn init(
+        &mut self,
+        config: &Config,
+        transaction: &mut Transaction,
+    ) -> Result<Self, ApiError> {
+        self.validate_schedule()?;
+        self.fill_missing_info(config)?;
+        self.apply_location_precision()?;
+        self.describe_coordinates();
         match transaction.query_one(
             "insert into alerts (
                 alert_type,
@@ -202,8 +459,22 @@ impl Alert {
                 display_email,
                 display_phone,
                 track_location,
-                created_by
-            ) values ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                created_by,
+                expires_at,
+                starts_at,
+                ends_at,
+                notified_at,
+                location_precision
+            ) values (
+                $1, $2, $3, $4, $5, $6, $7, $8, $9,
+                coalesce($10::timestamp, $12::timestamp, coalesce($11::timestamp, now()) + make_interval(
+                    mins => (select default_ttl_minutes from alert_types where name = $1)
+                )),
+                $11,
+                $12,
+                case when $11 <= now() + make_interval(mins => $13) then now() end,
+                $14
+            )
             returning *
             ",
             &[
@@ -216,21 +487,24 @@ impl Alert {
                 &self.display_phone,
                 &self.track_location,
                 &self.created_by,
+                &self.expires_at,
+                &self.starts_at,
+                &self.ends_at,
+                &config.alerts.notification_lead_minutes,
+                &self.location_precision,
             ],
         ) {
             Ok(row) => {
                 let mut alert = alert!(row);
                 alert.populate(transaction);
+                TrackPoint::record_current_position(&alert, transaction)?;
                 Ok(alert)
             }
-            Err(err) => {
-                error!("{}", err);
-                Err(String::from("Could not initialize alert"))
-            }
+            Err(err) => Err(err.into()),
         }
     }
 
-    pub fn delete(&self, transaction: &mut Transaction) -> Result<Self, String> {
+    pub fn delete(&self, transaction: &mut Transaction) -> Result<Self, ApiError> {
         match transaction.query_one(
             "delete from alerts where id = $1
             returning *
@@ -242,17 +516,20 @@ impl Alert {
                 alert.populate(transaction);
                 Ok(alert)
             }
-            Err(err) => {
-                error!("{}", err);
-                Err(String::from("Could not delete alert"))
-            }
+            Err(err) => Err(err.into()),
         }
     }
 
-    pub fn update(&self, new: &mut Self, transaction: &mut Transaction) -> Result<Self, String> {
-        if let Err(err) = new.fill_missing_info() {
-            return Err(err);
-        }
+    pub fn update(
+        &self,
+        new: &mut Self,
+        config: &Config,
+        transaction: &mut Transaction,
+    ) -> Result<Self, ApiError> {
+        new.validate_schedule()?;
+        new.fill_missing_info(config)?;
+        new.apply_location_precision()?;
+        new.describe_coordinates();
         match transaction.query_one(
             "update alerts set
                 alert_type = $1,
@@ -263,6 +540,10 @@ impl Alert {
                 display_email = $6,
                 display_phone = $7,
                 track_location = $8,
+                starts_at = $11,
+                ends_at = $12,
+                expires_at = coalesce($10, $12, expires_at),
+                location_precision = $13,
                 updated_at = now()
             where id = $9 
             returning *
@@ -277,56 +558,102 @@ impl Alert {
                 &new.display_phone,
                 &new.track_location,
                 &self.id,
+                &new.expires_at,
+                &new.starts_at,
+                &new.ends_at,
+                &new.location_precision,
             ],
         ) {
             Ok(row) => {
                 let mut alert = alert!(row);
                 alert.populate(transaction);
+                TrackPoint::record_current_position(&alert, transaction)?;
                 Ok(alert)
             }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Where the user's tracked alerts will follow them to with this upload, if they have any.
+    pub fn tracking_destination(
+        user: &User,
+        locations: &[Location],
+        config: &Config,
+        transaction: &mut Transaction,
+    ) -> Option<Location> {
+        // Without geocoding there is no place to look up
+        config.mapquest.as_ref()?;
+
+        let uploaded_at = Utc::now().naive_utc();
+        let latest = locations
+            .iter()
+            .max_by_key(|location| location.recorded_at.unwrap_or(uploaded_at))?;
+
+        match transaction.query_one(
+            "select exists(
+                select 1 from alerts
+                where created_by = $1 and track_location and not is_resolved
+            ) as tracking
+            ",
+            &[&user.email],
+        ) {
+            Ok(row) if row.get::<_, bool>("tracking") => {
+                let grid = PrivacySettings::get_for_user(user.id, transaction)
+                    .location_grid(&config.locations);
+                Some(latest.snapped(grid))
+            }
+            Ok(_) => None,
             Err(err) => {
                 error!("{}", err);
-                Err(String::from("Could not update alert"))
+                None
             }
         }
     }
 
-    pub fn update_tracking_alert(location: &Location, transaction: &mut Transaction) -> Vec<Self> {
-        if let Some(user) = User::from_id(location.user_id, transaction) {
-            if let MapquestResult::Success(address) =
-                get_address(location.latitude, location.longitude)
+    /// Moves the user's tracked alerts to the location, at the looked up `place` if it is for
+    /// the same point.
+    pub fn update_tracking_alert(
+        location: &Location,
+        place: Option<&TrackingPlace>,
+        transaction: &mut Transaction,
+    ) -> Vec<Self> {
+        let user = match User::from_id(location.user_id, transaction) {
+            Some(user) => user,
+            None => return Vec::new(),
+        };
+
+        let place = match place {
+            Some(place)
+                if place.latitude == location.latitude && place.longitude == location.longitude =>
             {
-                return match transaction.query(
-                    "update alerts set
-                    latitude = $1,
-                    longitude = $2,
-                    place = $3,
-                    updated_at = now()
-                where 
-                    created_by = $4
-                    and track_location
-                returning *
-                ",
-                    &[
-                        &location.latitude,
-                        &location.longitude,
-                        &address,
-                        &user.email,
-                    ],
-                ) {
-                    Ok(rows) => {
-                        info!("updated location of {} alerts", rows.len());
-                        rows.iter().map(|row| alert!(row)).collect::<Vec<Alert>>()
-                    }
-                    Err(err) => {
-                        error!("{}", err);
-                        Vec::new()
-                    }
-                };
+                place.place.clone()
             }
-        }
+            _ => coordinate_place(location.latitude, location.longitude),
+        };
 
-        Vec::new()
+        match transaction.query(
+            "update alerts set
+                latitude = $1,
+                longitude = $2,
+                place = $3,
+                updated_at = now()
+            where
+                created_by = $4
+                and track_location
+                and not is_resolved
+            returning *
+            ",
+            &[&location.latitude, &location.longitude, &place, &user.email],
+        ) {
+            Ok(rows) => {
+                info!("updated location of {} alerts", rows.len());
+                rows.iter().map(|row| alert!(row)).collect::<Vec<Alert>>()
+            }
+            Err(err) => {
+                error!("{}", err);
+                Vec::new()
+            }
+        }
     }
 
     pub fn get_by_id(id: i64, transaction: &mut Transaction) -> Option<Alert> {
@@ -347,10 +674,13 @@ impl Alert {
         }
     }
 
-    pub fn resolve(&self, transaction: &mut Transaction) -> Result<Self, String> {
+    pub fn resolve(&self, transaction: &mut Transaction) -> Result<Self, ApiError> {
         match transaction.query_one(
             "update alerts set 
                 is_resolved = true,
+                track_location = false,
+                resolved_at = now(),
+                resolved_by = 'creator',
                 updated_at = now()
             where id = $1
             returning *
@@ -358,10 +688,88 @@ impl Alert {
             &[&self.id],
         ) {
             Ok(row) => Ok(alert!(row)),
-            Err(err) => {
-                error!("{}", err);
-                Err(String::from("Could not mark alert as resolved"))
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Resolves every unresolved alert whose expiry has passed on behalf of the system.
+    pub fn resolve_expired(transaction: &mut Transaction) -> Result<Vec<Self>, ApiError> {
+        match transaction.query(
+            "update alerts set
+                is_resolved = true,
+                track_location = false,
+                resolved_at = now(),
+                resolved_by = 'system',
+                updated_at = now()
+            where not is_resolved
+                and expires_at <= now()
+            returning *
+            ",
+            &[],
+        ) {
+            Ok(rows) => Ok(rows.iter().map(|row| alert!(row)).collect::<Vec<Alert>>()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Whether the alert still counts as ongoing, expired alerts stop counting before the
+    /// expiry job gets to resolve them.
+    pub fn is_active(&self) -> bool {
+        !self.is_resolved
+            && self
+                .expires_at
+                .map_or(true, |expires_at| expires_at > Utc::now().naive_utc())
+    }
+
+    /// Whether nearby users should be notified yet, planned events wait until shortly before
+    /// they start.
+    pub fn is_notification_due(&self, config: &AlertConfig) -> bool {
+        self.starts_at.map_or(true, |starts_at| {
+            starts_at - chrono::Duration::minutes(i64::from(config.notification_lead_minutes))
+                <= Utc::now().naive_utc()
+        })
+    }
+
+    /// Marks the planned events starting within the notification lead time as notified, and
+    /// returns them so their notifications can be queued.
+    pub fn claim_due_announcements(
+        config: &AlertConfig,
+        transaction: &mut Transaction,
+    ) -> Result<Vec<Self>, ApiError> {
+        match transaction.query(
+            "update alerts set
+                notified_at = now()
+            where starts_at is not null
+                and notified_at is null
+                and not is_resolved
+                and (expires_at is null or expires_at > now())
+                and starts_at <= now() + make_interval(mins => $1)
+            returning *
+            ",
+            &[&config.notification_lead_minutes],
+        ) {
+            Ok(rows) => Ok(rows.iter().map(|row| alert!(row)).collect::<Vec<Alert>>()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn validate_schedule(&self) -> Result<(), ApiError> {
+        let now = Utc::now().naive_utc();
+
+        if let Some(expires_at) = self.expires_at {
+            if expires_at <= now {
+                return Err(ApiError::validation("expiresAt", "Must be in the future"));
+            }
+        }
+
+        match (self.starts_at, self.ends_at) {
+            (_, Some(ends_at)) if ends_at <= now => {
+                Err(ApiError::validation("endsAt", "Must be in the future"))
+            }
+            (Some(starts_at), Some(ends_at)) if ends_at <= starts_at => {
+                Err(ApiError::validation("endsAt", "Must be after startsAt"))
             }
+            _ => Ok(()),
         }
     }
 
@@ -372,84 +780,465 @@ impl Alert {
         }
     }
 
-    pub fn fill_missing_info(&mut self) -> Result<(), String> {
+    /// Same as `populate` for rows of listing queries, which join the creator's `name` and
+    /// `phone` in as `creator_name` and `creator_phone` so no query is needed per alert.
+    fn populate_from_row(&mut self, row: &Row, transaction: &mut Transaction) {
+        self.alert_type_obj = AlertType::get_by_name(&self.alert_type, transaction);
+        if let Some(name) = row.get::<_, Option<String>>("creator_name") {
+            self.user_info = Some(AlertUserInfo {
+                name,
+                email: if self.display_email {
+                    Some(self.created_by.clone())
+                } else {
+                    Option::None
+                },
+                phone: if self.display_phone {
+                    row.get("creator_phone")
+                } else {
+                    Option::None
+                },
+            });
+        }
+    }
+
+    pub fn fill_missing_info(&mut self, config: &Config) -> Result<(), ApiError> {
         if let (Some(latitude), Some(longitude)) = (self.latitude, self.longitude) {
-            match get_address(latitude, longitude) {
+            match get_address(latitude, longitude, config) {
                 MapquestResult::Success(address) => {
                     self.place = Some(address);
                     Ok(())
                 }
-                MapquestResult::NoAPIKey | MapquestResult::NoValue | MapquestResult::NoResult => {
-                    Err(String::from("Could not convert location to address!"))
-                }
+                // The place falls back to the coordinates once they are rounded
+                MapquestResult::Disabled => Ok(()),
+                MapquestResult::RequestFailed
+                | MapquestResult::NoValue
+                | MapquestResult::NoResult => Err(ApiError::upstream(
+                    "mapquest",
+                    "Could not convert location to address!",
+                )),
             }
-        } else if let Some(place) = &self.place {
-            match get_location(place.to_string()) {
+        } else if let Some(place) = self
+            .place
+            .as_deref()
+            .filter(|place| !place.trim().is_empty())
+        {
+            match get_location(place.to_string(), config) {
                 MapquestResult::Success((latitude, longitude)) => {
                     self.latitude = Some(latitude);
                     self.longitude = Some(longitude);
                     Ok(())
                 }
-                MapquestResult::NoAPIKey | MapquestResult::NoValue | MapquestResult::NoResult => {
-                    Err(String::from("Could not convert address to locaiton!"))
-                }
+                MapquestResult::Disabled => Err(ApiError::validation(
+                    "latitude",
+                    "Geocoding is disabled, must provide (lat, long)!",
+                )),
+                MapquestResult::RequestFailed
+                | MapquestResult::NoValue
+                | MapquestResult::NoResult => Err(ApiError::upstream(
+                    "mapquest",
+                    "Could not convert address to location!",
+                )),
             }
         } else {
-            Err(String::from("Must either provide (lat, long) or address!"))
+            Err(ApiError::validation(
+                "place",
+                "Must either provide (lat, long) or address!",
+            ))
         }
     }
 
-    pub fn get_notification_info(
+    // Without geocoding the coordinates are all there is to describe the place
+    fn describe_coordinates(&mut self) {
+        let missing = self
+            .place
+            .as_deref()
+            .map_or(true, |place| place.trim().is_empty());
+
+        if let (true, Some(latitude), Some(longitude)) = (missing, self.latitude, self.longitude) {
+            self.place = Some(coordinate_place(latitude, longitude));
+        }
+    }
+
+    /// Rounds the location to the alert's precision, snapping the coordinates to the grid and
+    /// rounding the place to match.
+    fn apply_location_precision(&mut self) -> Result<(), ApiError> {
+        let precision = match LocationPrecision::parse(&self.location_precision) {
+            Some(precision) => precision,
+            None => {
+                return Err(ApiError::validation(
+                    "locationPrecision",
+                    "Must be exact, block or neighbourhood",
+                ))
+            }
+        };
+
+        let cell_meters = match precision.grid_meters() {
+            Some(cell_meters) => cell_meters,
+            None => return Ok(()),
+        };
+
+        if self.track_location {
+            return Err(ApiError::validation(
+                "locationPrecision",
+                "Tracked alerts follow their creator and can't be rounded",
+            ));
+        }
+
+        if let (Some(latitude), Some(longitude)) = (self.latitude, self.longitude) {
+            let (latitude, longitude) =
+                snap_to_grid(f64::from(latitude), f64::from(longitude), cell_meters);
+            self.latitude = Some(latitude as f32);
+            self.longitude = Some(longitude as f32);
+        }
+
+        self.place = self
+            .place
+            .as_deref()
+            .map(|place| round_place(place, precision));
+
+        Ok(())
+    }
+
+    /// Records the users last accurately located in range of the alert, or zoned around it, who
+    /// weren't notified yet.
+    pub fn record_recipients(
         &self,
+        alert_type: &AlertType,
+        config: &LocationConfig,
         transaction: &mut Transaction,
-    ) -> Vec<AlertNotificationInfo> {
+    ) -> Result<Vec<AlertNotificationInfo>, ApiError> {
+        let radius_miles = f64::from(alert_type.notification_radius_miles);
+        let (latitude, longitude) = match (self.latitude, self.longitude) {
+            (Some(latitude), Some(longitude)) => (f64::from(latitude), f64::from(longitude)),
+            _ => return Ok(Vec::new()),
+        };
+        let bounds = BoundingBox::around(latitude, longitude, radius_miles);
+
         match transaction.query(
-            "select 
-                calculate_distance($1, $2, l.latitude, l.longitude) as distance,
-                fdt.token 
-            from firebase_device_tokens fdt
-            inner join locations l
-                on  fdt.user_id = l.user_id
-            where 
-                l.latitude > $1::real - $3::real
-                and l.latitude < $1::real + $3::real
-                and l.longitude > $2::real - $3::real
-                and l.longitude < $2::real + $3::real
+            "with creator as (
+                select id from users where email = $4
+            ),
+            candidates as (
+                select
+                    l.user_id,
+                    great_circle_distance(
+                        $1, $2, l.targeting_latitude, l.targeting_longitude
+                    ) as distance,
+                    null as zone
+                from locations l
+                left join notification_preferences p
+                    on l.user_id = p.user_id
+                where
+                    l.targeting_latitude between $6::float8 and $7::float8
+                    and l.targeting_longitude between $8::float8 and $9::float8
+                    and (l.targeting_accuracy is null or l.targeting_accuracy <= $12)
+                    and great_circle_distance($1, $2, l.targeting_latitude, l.targeting_longitude)
+                        <= least($3::float8, coalesce(p.radius_miles::float8, $3::float8))
+                union all
+                select z.user_id, null, z.name
+                from watch_zones z
+                where
+                    $1 between z.min_latitude and z.max_latitude
+                    and $2 between z.min_longitude and z.max_longitude
+                    and zone_contains(z.latitude, z.longitude, z.radius_miles, z.boundary, $1, $2)
+            ),
+            matched as (
+                select c.user_id, min(c.distance) as distance, min(c.zone) as zone
+                from candidates c
+                left join notification_preferences p
+                    on c.user_id = p.user_id
+                where
+                    (
+                        p.user_id is null
+                        or (
+                            $11 <= p.max_alert_level
+                            and not ($10 = any(p.muted_alert_types))
+                            and not (
+                                in_quiet_hours(p.quiet_hours_start, p.quiet_hours_end, p.timezone)
+                                and not (p.emergency_override and $11 = 1)
+                            )
+                        )
+                    )
+                    and c.user_id not in (select id from creator)
+                    and not exists (
+                        select 1 from blocked_users b, creator cr
+                        where (b.user_id = c.user_id and b.blocked_user_id = cr.id)
+                            or (b.user_id = cr.id and b.blocked_user_id = c.user_id)
+                    )
+                    and exists (
+                        select 1 from firebase_device_tokens fdt where fdt.user_id = c.user_id
+                    )
+                group by c.user_id
+            ),
+            recipients as (
+                insert into alert_recipients (alert_id, user_id)
+                select $5, user_id from matched
+                on conflict (alert_id, user_id) do nothing
+                returning user_id
+            )
+            select
+                fdt.user_id,
+                m.distance::real as distance,
+                m.zone,
+                fdt.token,
+                coalesce(p.distance_unit, 'mi') as distance_unit
+            from recipients r
+            inner join matched m
+                on r.user_id = m.user_id
+            inner join firebase_device_tokens fdt
+                on r.user_id = fdt.user_id
+            left join notification_preferences p
+                on r.user_id = p.user_id
+            order by fdt.user_id
             ",
-            &[&self.latitude, &self.longitude, &LAT_LNG_VIEW_PORT],
+            &[
+                &latitude,
+                &longitude,
+                &radius_miles,
+                &self.created_by,
+                &self.id,
+                &bounds.min_latitude,
+                &bounds.max_latitude,
+                &bounds.min_longitude,
+                &bounds.max_longitude,
+                &alert_type.name,
+                &alert_type.alert_level,
+                &config.max_targeting_accuracy_meters,
+            ],
         ) {
-            Ok(rows) => rows
+            Ok(rows) => Ok(rows
                 .iter()
                 .map(|row| alert_notification_info!(row))
-                .collect::<Vec<AlertNotificationInfo>>(),
+                .collect::<Vec<AlertNotificationInfo>>()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// One page of the alerts in the viewport matching the filters, along with the cursor of
+    /// the next page if there is one.
+    pub fn get_by_viewport(
+        ne_lat: f32,
+        ne_lng: f32,
+        sw_lat: f32,
+        sw_lng: f32,
+        filters: &AlertFilters,
+        requested_by: &str,
+        transaction: &mut Transaction,
+    ) -> (Vec<Self>, Option<AlertCursor>) {
+        // Only these fixed fragments are formatted into the query, never user input
+        let (comparison, direction) = match filters.sort {
+            AlertSort::Newest => ("<", "desc"),
+            AlertSort::Oldest => (">", "asc"),
+        };
+
+        let query = format!(
+            "select
+                a.*,
+                u.name as creator_name,
+                u.phone as creator_phone
+            from alerts a
+            inner join alert_types t
+                on a.alert_type = t.name
+            left join users u
+                on a.created_by = u.email
+            where
+                a.latitude < $1
+                and a.longitude < $2
+                and a.latitude > $3
+                and a.longitude > $4
+                and ($5::bool is null
+                    or (a.is_resolved or coalesce(a.expires_at <= now(), false)) = $5)
+                and ($6::text[] is null or a.alert_type = any($6))
+                and ($7::smallint is null or t.alert_level <= $7)
+                and ($8::timestamp is null or a.created_at >= $8)
+                and ($9::timestamp is null or a.created_at < $9)
+                and ($10::text is null or (
+                    a.created_by = $10 and (a.display_email or a.created_by = $11)
+                ))
+                and ($12::timestamp is null or (a.created_at, a.id) {} ($12, $13))
+                and ($15::text is null
+                    or alert_status(a.is_resolved, a.starts_at, a.expires_at) = $15)
+            order by a.created_at {}, a.id {}
+            limit $14
+            ",
+            comparison, direction, direction
+        );
+
+        // One extra row tells whether there is a next page
+        match transaction.query(
+            query.as_str(),
+            &[
+                &ne_lat,
+                &ne_lng,
+                &sw_lat,
+                &sw_lng,
+                &filters.resolved,
+                &filters.alert_types,
+                &filters.min_level,
+                &filters.created_after,
+                &filters.created_before,
+                &filters.creator,
+                &requested_by,
+                &filters.cursor.map(|cursor| cursor.created_at),
+                &filters.cursor.map(|cursor| cursor.id),
+                &(filters.limit + 1),
+                &filters.status.map(AlertStatus::name),
+            ],
+        ) {
+            Ok(rows) => {
+                let mut res = Vec::new();
+                for row in rows.iter().take(filters.limit as usize) {
+                    let mut alert = alert!(row);
+                    alert.populate_from_row(row, transaction);
+                    res.push(alert);
+                }
+
+                let next_cursor = if rows.len() as i64 > filters.limit {
+                    res.last().and_then(|alert| {
+                        alert.created_at.map(|created_at| AlertCursor {
+                            created_at,
+                            id: alert.id,
+                        })
+                    })
+                } else {
+                    None
+                };
+
+                (res, next_cursor)
+            }
             Err(err) => {
                 error!("{}", err);
-                Vec::new()
+                (Vec::new(), None)
             }
         }
     }
 
-    pub fn get_by_viewport(
+    /// Groups the unresolved alerts in the viewport into square grid cells `cell_size` degrees
+    /// wide.
+    /// Active alerts grouped into grid cells `cell_size` degrees wide, with the ids of the
+    /// alerts in cells with fewer than `min_cluster_size` of them.
+    pub fn get_clusters(
         ne_lat: f32,
         ne_lng: f32,
         sw_lat: f32,
         sw_lng: f32,
+        cell_size: f64,
+        min_cluster_size: i64,
+        transaction: &mut Transaction,
+    ) -> Vec<AlertCluster> {
+        match transaction.query(
+            "select
+                avg(a.latitude) as latitude,
+                avg(a.longitude) as longitude,
+                count(*) as count,
+                min(t.alert_level) as highest_level,
+                case when count(*) < $6
+                    then array_agg(a.id order by a.created_at desc)
+                end as alert_ids
+            from alerts a
+            inner join alert_types t
+                on a.alert_type = t.name
+            where alert_status(a.is_resolved, a.starts_at, a.expires_at) = 'active'
+                and a.latitude < $1
+                and a.longitude < $2
+                and a.latitude > $3
+                and a.longitude > $4
+            group by floor(a.latitude / $5::float8), floor(a.longitude / $5::float8)
+            ",
+            &[
+                &ne_lat,
+                &ne_lng,
+                &sw_lat,
+                &sw_lng,
+                &cell_size,
+                &min_cluster_size,
+            ],
+        ) {
+            Ok(rows) => rows
+                .iter()
+                .map(|row| alert_cluster!(row))
+                .collect::<Vec<AlertCluster>>(),
+            Err(err) => {
+                error!("{}", err);
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn get_by_ids(ids: &[i64], transaction: &mut Transaction) -> Vec<Self> {
+        match transaction.query(
+            "select
+                a.*,
+                u.name as creator_name,
+                u.phone as creator_phone
+            from alerts a
+            left join users u
+                on a.created_by = u.email
+            where a.id = any($1)
+            order by a.created_at desc
+            ",
+            &[&ids],
+        ) {
+            Ok(rows) => {
+                let mut res = Vec::new();
+                for row in rows {
+                    let mut alert = alert!(row);
+                    alert.populate_from_row(&row, transaction);
+                    res.push(alert);
+                }
+                res
+            }
+            Err(err) => {
+                error!("{}", err);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Unresolved alerts within `radius_miles` of the point, closest first, with their distance
+    /// in miles.
+    pub fn get_nearby(
+        latitude: f64,
+        longitude: f64,
+        radius_miles: f64,
         transaction: &mut Transaction,
     ) -> Vec<Self> {
+        let bounds = BoundingBox::around(latitude, longitude, radius_miles);
+
         match transaction.query(
-            "select * from alerts where 
-                latitude < $1
-                and longitude < $2
-                and latitude > $3
-                and longitude > $4
+            "select * from (
+                select
+                    a.*,
+                    u.name as creator_name,
+                    u.phone as creator_phone,
+                    great_circle_distance($1, $2, a.latitude, a.longitude) as distance
+                from alerts a
+                left join users u
+                    on a.created_by = u.email
+                where not a.is_resolved
+                    and (a.expires_at is null or a.expires_at > now())
+                    and a.latitude between $4::float8 and $5::float8
+                    and a.longitude between $6::float8 and $7::float8
+            ) nearby
+            where distance <= $3
+            order by distance
             ",
-            &[&ne_lat, &ne_lng, &sw_lat, &sw_lng],
+            &[
+                &latitude,
+                &longitude,
+                &radius_miles,
+                &bounds.min_latitude,
+                &bounds.max_latitude,
+                &bounds.min_longitude,
+                &bounds.max_longitude,
+            ],
         ) {
             Ok(rows) => {
                 let mut res = Vec::new();
                 for row in rows {
                     let mut alert = alert!(row);
-                    alert.populate(transaction);
+                    alert.distance = Some(row.get("distance"));
+                    alert.populate_from_row(&row, transaction);
                     res.push(alert);
                 }
                 res
@@ -461,3 +1250,283 @@ impl Alert {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::database::test_client;
+    use log::{LevelFilter, Log, Metadata, Record};
+    use rocket::http::Status;
+    use std::cell::Cell;
+    use std::sync::Once;
+
+    #[test]
+    fn round_place_hides_the_street_number() {
+        let place = "221 Main St, Springfield, IL";
+
+        assert_eq!(round_place(place, LocationPrecision::Exact), place);
+        assert_eq!(
+            round_place(place, LocationPrecision::Block),
+            "Main St, Springfield, IL"
+        );
+        assert_eq!(
+            round_place(place, LocationPrecision::Neighbourhood),
+            "Springfield, IL"
+        );
+        assert_eq!(
+            round_place("Main St, Springfield", LocationPrecision::Block),
+            "Main St, Springfield"
+        );
+        assert_eq!(
+            round_place("Springfield", LocationPrecision::Neighbourhood),
+            "Springfield"
+        );
+    }
+
+    fn alert(location: &str) -> Alert {
+        serde_json::from_str(&format!(
+            r#"{{"alertType": "Theft", "displayEmail": true, "displayPhone": true,
+                "trackLocation": false, {}}}"#,
+            location
+        ))
+        .unwrap()
+    }
+
+    // What creating or updating an alert does to its location, with geocoding disabled
+    fn locate(alert: &mut Alert) -> Result<(), ApiError> {
+        alert.fill_missing_info(&Config::for_tests())?;
+        alert.apply_location_precision()?;
+        alert.describe_coordinates();
+        Ok(())
+    }
+
+    #[test]
+    fn coordinates_describe_the_place_without_geocoding() {
+        let mut exact = alert(r#""latitude": 40.7128, "longitude": -74.006"#);
+        locate(&mut exact).unwrap();
+        assert_eq!(exact.place.as_deref(), Some("40.71280, -74.00600"));
+
+        let mut rounded = alert(
+            r#""latitude": 40.7128, "longitude": -74.006, "locationPrecision": "neighbourhood""#,
+        );
+        locate(&mut rounded).unwrap();
+        assert_ne!(rounded.latitude, Some(40.7128));
+        assert_eq!(
+            rounded.place,
+            Some(format!(
+                "{:.5}, {:.5}",
+                rounded.latitude.unwrap(),
+                rounded.longitude.unwrap()
+            ))
+        );
+
+        let mut named = alert(r#""latitude": 40.7128, "longitude": -74.006, "place": "City Hall""#);
+        locate(&mut named).unwrap();
+        assert_eq!(named.place.as_deref(), Some("City Hall"));
+    }
+
+    #[test]
+    fn alerts_without_coordinates_need_geocoding() {
+        let mut addressed = alert(r#""place": "City Hall""#);
+        assert_eq!(
+            locate(&mut addressed).unwrap_err().status(),
+            Status::UnprocessableEntity
+        );
+
+        let mut nowhere = alert(r#""place": " ""#);
+        assert_eq!(
+            locate(&mut nowhere).unwrap_err().status(),
+            Status::UnprocessableEntity
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn record_recipients_reaches_nearby_and_zoned_users_once() {
+        let mut client = test_client();
+        let mut transaction = client.transaction().unwrap();
+
+        transaction
+            .batch_execute(
+                "insert into users (id, name, email, password, phone) values
+                    (-101, 'Creator', 'creator@recipients.test', 'x', '1'),
+                    (-102, 'Nearby', 'nearby@recipients.test', 'x', '2'),
+                    (-103, 'Zoned', 'zoned@recipients.test', 'x', '3'),
+                    (-104, 'Far', 'far@recipients.test', 'x', '4');
+                insert into locations (
+                    user_id, latitude, longitude, targeting_latitude, targeting_longitude
+                ) values
+                    (-101, 40, -75, 40, -75),
+                    (-102, 40.05, -75, 40.05, -75),
+                    (-103, 45, -75, 45, -75),
+                    (-104, 45, -75, 45, -75);
+                insert into firebase_device_tokens (user_id, token) values
+                    (-101, 'creator'), (-102, 'nearby'), (-103, 'zoned'), (-104, 'far');
+                insert into watch_zones (
+                    user_id, name, kind, latitude, longitude, radius_miles,
+                    min_latitude, max_latitude, min_longitude, max_longitude
+                ) values (-103, 'Home', 'circle', 40, -75, 1, 39.98, 40.02, -75.02, -74.98);
+                ",
+            )
+            .unwrap();
+
+        let id: i64 = transaction
+            .query_one(
+                "insert into alerts (alert_type, place, latitude, longitude, created_by)
+                values ('Theft', 'Main Street', 40, -75, 'creator@recipients.test')
+                returning id
+                ",
+                &[],
+            )
+            .unwrap()
+            .get("id");
+
+        let alert = Alert::get_by_id(id, &mut transaction).unwrap();
+        let alert_type = AlertType::get_by_name(&alert.alert_type, &mut transaction).unwrap();
+
+        let config = Config::for_tests();
+        let recipients = alert
+            .record_recipients(&alert_type, &config.locations, &mut transaction)
+            .unwrap();
+        let recipients = recipients
+            .iter()
+            .map(|info| {
+                (
+                    info.token.as_str(),
+                    info.distance.is_some(),
+                    info.zone.as_deref(),
+                )
+            })
+            .collect::<Vec<(&str, bool, Option<&str>)>>();
+        assert_eq!(
+            recipients,
+            vec![("zoned", false, Some("Home")), ("nearby", true, None)]
+        );
+
+        let again = alert
+            .record_recipients(&alert_type, &config.locations, &mut transaction)
+            .unwrap();
+        assert!(again.is_empty());
+    }
+
+    thread_local! {
+        static STATEMENTS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    // Counts the statements the database client sends, which it logs as it executes them.
+    // Queries run on the calling thread, so each test only sees its own.
+    struct StatementCounter;
+
+    impl Log for StatementCounter {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.target().starts_with("tokio_postgres")
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) && record.args().to_string().starts_with("executing")
+            {
+                STATEMENTS.with(|count| count.set(count.get() + 1));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn statements_issued() -> usize {
+        static COUNTER: Once = Once::new();
+        COUNTER.call_once(|| {
+            log::set_logger(&StatementCounter).expect("Couldn't count statements");
+            log::set_max_level(LevelFilter::Debug);
+        });
+
+        STATEMENTS.with(Cell::get)
+    }
+
+    fn viewport_page(limit: i64, transaction: &mut Transaction) -> (Vec<Alert>, usize) {
+        let filters = AlertFilters {
+            resolved: None,
+            status: None,
+            alert_types: None,
+            min_level: None,
+            created_after: None,
+            created_before: None,
+            creator: None,
+            sort: AlertSort::Newest,
+            cursor: None,
+            limit,
+        };
+
+        let before = statements_issued();
+        let (alerts, _) = Alert::get_by_viewport(
+            -59.0,
+            -29.0,
+            -61.0,
+            -31.0,
+            &filters,
+            "viewer@viewport.test",
+            transaction,
+        );
+        (alerts, statements_issued() - before)
+    }
+
+    #[test]
+    #[ignore]
+    fn get_by_viewport_issues_the_same_statements_for_any_page_size() {
+        let mut client = test_client();
+        let mut transaction = client.transaction().unwrap();
+        transaction
+            .batch_execute(
+                "insert into users (id, name, email, password, phone)
+                    select -400 - n, 'Creator', 'creator' || n || '@viewport.test', 'x', n::text
+                    from generate_series(1, 10) n;
+                insert into alerts (alert_type, place, latitude, longitude, created_by)
+                    select 'Theft', 'Open sea', -60 + n * 0.01, -30, 'creator' || n || '@viewport.test'
+                    from generate_series(1, 10) n;
+                ",
+            )
+            .unwrap();
+
+        // Loads the alert types into memory
+        viewport_page(1, &mut transaction);
+
+        let (one, statements_for_one) = viewport_page(1, &mut transaction);
+        let (ten, statements_for_ten) = viewport_page(10, &mut transaction);
+
+        assert_eq!(one.len(), 1);
+        assert_eq!(ten.len(), 10);
+        assert!(ten.iter().all(|alert| alert.user_info.is_some()));
+        assert!(statements_for_one > 0);
+        assert_eq!(statements_for_one, statements_for_ten);
+    }
+
+    #[test]
+    #[ignore]
+    fn clusters_list_ids_of_small_active_clusters_only() {
+        let mut client = test_client();
+        let mut transaction = client.transaction().unwrap();
+        transaction
+            .batch_execute(
+                "insert into users (id, name, email, password, phone)
+                values (-601, 'Creator', 'creator@clusters.test', 'x', '1');
+                insert into alerts (alert_type, place, latitude, longitude, created_by, starts_at)
+                values
+                    ('Theft', 'Crowd', -70.1, -40.1, 'creator@clusters.test', null),
+                    ('Theft', 'Crowd', -70.2, -40.2, 'creator@clusters.test', null),
+                    ('Theft', 'Crowd', -70.3, -40.3, 'creator@clusters.test', null),
+                    ('Theft', 'Alone', -75.5, -45.5, 'creator@clusters.test', null),
+                    ('Theft', 'Later', -75.6, -45.6, 'creator@clusters.test',
+                        now() + interval '1 day');
+                ",
+            )
+            .unwrap();
+
+        let mut clusters =
+            Alert::get_clusters(-65.0, -35.0, -80.0, -50.0, 5.0, 3, &mut transaction)
+                .into_iter()
+                .map(|cluster| (cluster.count, cluster.alert_ids.len()))
+                .collect::<Vec<(i64, usize)>>();
+        clusters.sort();
+
+        assert_eq!(clusters, vec![(1, 1), (3, 0)]);
+    }
+}
@@ -1,15 +1,25 @@
+use crate::config::Config;
+use crate::models::error::ApiError;
 use chrono::{Duration, Utc};
 use jsonwebtoken::{decode, encode, Algorithm, Header, Validation};
 use postgres::Transaction;
+use rand::rngs::OsRng;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
-use std::env;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
 use std::ops::Deref;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
-    sub: String,
-    iat: i64,
-    exp: i64,
+    pub sub: String,
+    pub iat: i64,
+    pub exp: i64,
+
+    // Session the access token was issued for, only present on auth tokens
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sid: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,56 +41,92 @@ pub struct BasicAuth {
     pub password: Option<String>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenType {
     Auth,
     Verification,
     PasswordReset,
 }
 
-#[macro_export]
-macro_rules! secret {
-    ($token_type:expr) => {
-        match $token_type {
-            TokenType::Auth => env::var("AUTH_SECRET").unwrap(),
-            TokenType::Verification => env::var("VERIFICATION_SECRET").unwrap(),
-            TokenType::PasswordReset => env::var("PASSWORD_RESET_SECRET").unwrap(),
+fn sign_claims(
+    claims: &Claims,
+    token_type: TokenType,
+    config: &Config,
+) -> Result<String, ApiError> {
+    let secret = config.auth.secret(&token_type);
+
+    match encode(&Header::new(Algorithm::HS256), claims, secret.as_ref()) {
+        Ok(token) => Ok(token),
+        Err(err) => {
+            error!("{}", err.to_string());
+            Err(ApiError::Internal(String::from(
+                "Could not sign authentication token.",
+            )))
         }
-    };
+    }
 }
 
-pub fn generate_token(identifier: String, token_type: TokenType) -> Result<String, String> {
-    let secret = secret!(token_type);
-
+pub fn generate_token(
+    identifier: String,
+    token_type: TokenType,
+    config: &Config,
+) -> Result<String, ApiError> {
     let now = Utc::now();
     let claims = Claims {
         exp: (now + Duration::days(90)).timestamp(),
         iat: now.timestamp(),
         sub: identifier,
+        sid: None,
     };
 
-    match encode(
-        &Header::new(Algorithm::HS256),
-        &claims,
-        secret.as_str().as_ref(),
-    ) {
-        Ok(token) => Ok(token),
-        Err(err) => {
-            error!("{}", err.to_string());
-            return Err(String::from("Could not sign authentication token."));
-        }
-    }
+    sign_claims(&claims, token_type, config)
+}
+
+/// Short-lived auth token tied to a session, so it stops working once the session is revoked.
+pub fn generate_access_token(
+    identifier: String,
+    session_id: i64,
+    config: &Config,
+) -> Result<String, ApiError> {
+    let now = Utc::now();
+    let claims = Claims {
+        exp: (now + Duration::minutes(config.auth.access_token_minutes)).timestamp(),
+        iat: now.timestamp(),
+        sub: identifier,
+        sid: Some(session_id),
+    };
+
+    sign_claims(&claims, TokenType::Auth, config)
+}
+
+/// Opaque random token handed to the client, only its hash is ever stored.
+pub fn generate_refresh_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().fold(String::new(), |mut token, byte| {
+        let _ = write!(token, "{:02x}", byte);
+        token
+    })
+}
+
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
 }
 
-pub fn validate_token(token: String, token_type: TokenType) -> Result<String, String> {
-    let secret = secret!(token_type);
+pub fn validate_token(
+    token: String,
+    token_type: TokenType,
+    config: &Config,
+) -> Result<Claims, ApiError> {
+    let secret = config.auth.secret(&token_type);
 
     match decode::<Claims>(
         token.as_str(),
-        secret.as_str().as_ref(),
+        secret.as_ref(),
         &Validation::new(Algorithm::HS256),
     ) {
-        Ok(verified_token) => Ok(verified_token.claims.sub),
-        Err(err) => Err(err.to_string()),
+        Ok(verified_token) => Ok(verified_token.claims),
+        Err(err) => Err(ApiError::Unauthorized(err.to_string())),
     }
 }
 
@@ -89,7 +135,7 @@ pub fn store_token(
     token_type: TokenType,
     created_for: String,
     transaction: &mut Transaction,
-) -> Result<String, String> {
+) -> Result<String, ApiError> {
     match transaction.query(
         "delete from tokens where created_at < now() - interval '7 days'
         ",
@@ -118,10 +164,7 @@ pub fn store_token(
         &[&token, &token_type_text, &created_for],
     ) {
         Ok(row) => Ok(row.get(0)),
-        Err(err) => {
-            error!("{}", err);
-            Err(String::from(""))
-        }
+        Err(err) => Err(err.into()),
     }
 }
 
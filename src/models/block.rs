@@ -0,0 +1,105 @@
+use crate::models::error::ApiError;
+use crate::models::user::User;
+use chrono::NaiveDateTime;
+use postgres::Transaction;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockedUser {
+    #[serde(rename = "userId")]
+    pub user_id: i64,
+
+    pub name: String,
+
+    #[serde(rename = "blockedAt")]
+    pub blocked_at: Option<NaiveDateTime>,
+}
+
+#[macro_export]
+macro_rules! blocked_user {
+    ($row:expr) => {
+        BlockedUser {
+            user_id: $row.get("user_id"),
+            name: $row.get("name"),
+            blocked_at: $row.get("blocked_at"),
+        }
+    };
+}
+
+impl BlockedUser {
+    /// Blocks the creator of an alert, so the user learns nothing beyond what the alert showed.
+    pub fn block_alert_creator(
+        user: &User,
+        created_by: &str,
+        transaction: &mut Transaction,
+    ) -> Result<Self, ApiError> {
+        match transaction.query_opt(
+            "with blocked as (
+                insert into blocked_users (user_id, blocked_user_id)
+                select $1, u.id from users u where u.email = $2
+                on conflict (user_id, blocked_user_id) do update set
+                    created_at = blocked_users.created_at
+                returning blocked_user_id, created_at
+            )
+            select
+                b.blocked_user_id as user_id,
+                u.name,
+                b.created_at as blocked_at
+            from blocked b
+            inner join users u
+                on b.blocked_user_id = u.id
+            ",
+            &[&user.id, &created_by],
+        ) {
+            Ok(Some(row)) => Ok(blocked_user!(row)),
+            Ok(None) => Err(ApiError::NotFound(String::from(
+                "The creator of this alert no longer exists",
+            ))),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn unblock(
+        blocked_user_id: i64,
+        user: &User,
+        transaction: &mut Transaction,
+    ) -> Result<(), ApiError> {
+        match transaction.execute(
+            "delete from blocked_users where user_id = $1 and blocked_user_id = $2
+            ",
+            &[&user.id, &blocked_user_id],
+        ) {
+            Ok(0) => Err(ApiError::NotFound(format!(
+                "User {} is not blocked",
+                blocked_user_id
+            ))),
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn get_for_user(user: &User, transaction: &mut Transaction) -> Vec<Self> {
+        match transaction.query(
+            "select
+                b.blocked_user_id as user_id,
+                u.name,
+                b.created_at as blocked_at
+            from blocked_users b
+            inner join users u
+                on b.blocked_user_id = u.id
+            where b.user_id = $1
+            order by b.created_at desc
+            ",
+            &[&user.id],
+        ) {
+            Ok(rows) => rows
+                .iter()
+                .map(|row| blocked_user!(row))
+                .collect::<Vec<BlockedUser>>(),
+            Err(err) => {
+                error!("{}", err);
+                Vec::new()
+            }
+        }
+    }
+}
@@ -1,9 +1,13 @@
+use crate::config::DatabaseConfig;
 use postgres::{Client, NoTls};
-use std::env;
+use r2d2::{Pool, PooledConnection};
+use r2d2_postgres::PostgresConnectionManager;
 use std::ops::{Deref, DerefMut};
 
+pub type PGPool = Pool<PostgresConnectionManager<NoTls>>;
+
 pub struct PGConnection {
-    pub client: Client,
+    pub client: PooledConnection<PostgresConnectionManager<NoTls>>,
 }
 
 impl Deref for PGConnection {
@@ -19,25 +23,50 @@ impl DerefMut for PGConnection {
     }
 }
 
+// Timestamps are stored without a time zone in UTC, so now() has to be in UTC as well
+fn connection_config(url: &str) -> Result<postgres::Config, String> {
+    match url.parse::<postgres::Config>() {
+        Ok(mut connection_config) => {
+            connection_config.options("-c TimeZone=UTC");
+            Ok(connection_config)
+        }
+        Err(err) => {
+            error!("{}", err);
+            Err(String::from("Invalid postgres connection settings"))
+        }
+    }
+}
+
 impl PGConnection {
-    pub fn connect() -> Result<Self, String> {
-        let connection_str = format!(
-            "{}://{}:{}@{}:{}/{}",
-            env::var("DB_TYPE").unwrap(),
-            env::var("DB_USER").unwrap(),
-            env::var("DB_PASSWORD").unwrap(),
-            env::var("DB_HOST").unwrap(),
-            env::var("DB_PORT").unwrap(),
-            env::var("DB_NAME").unwrap()
-        );
-        match Client::connect(connection_str.as_str(), NoTls) {
-            Ok(client) => Ok(PGConnection { client }),
+    pub fn init_pool(config: &DatabaseConfig) -> Result<PGPool, String> {
+        let connection_config = connection_config(&config.url)?;
+
+        match Pool::builder()
+            .max_size(config.pool_size)
+            .idle_timeout(config.idle_timeout)
+            .connection_timeout(config.checkout_timeout)
+            .test_on_check_out(config.test_on_checkout)
+            .build(PostgresConnectionManager::new(connection_config, NoTls))
+        {
+            Ok(pool) => Ok(pool),
             Err(err) => {
                 error!("{}", err);
                 Err(String::from("Could not connect to postgres database"))
             }
         }
     }
+
+    pub fn from_pool(pool: &PGPool) -> Result<Self, String> {
+        match pool.get() {
+            Ok(client) => Ok(PGConnection { client }),
+            Err(err) => {
+                error!("{}", err);
+                Err(String::from(
+                    "All database connections are busy, please try again shortly.",
+                ))
+            }
+        }
+    }
 }
 
 #[macro_export]
@@ -47,13 +76,49 @@ macro_rules! transaction {
             Ok(transaction) => transaction,
             Err(_) => {
                 error!("Failed to get database transaction.");
-                return StandardResponse {
-                    status: Status::ServiceUnavailable,
-                    response: json!({
-                        "message": "The server is currently unavailable."
-                    })
-                };
+                return $crate::models::error::ApiError::Unavailable(String::from(
+                    "The server is currently unavailable.",
+                ))
+                .into();
             }
         }
     };
 }
+
+/// Client for the database at `TEST_DATABASE_URL` with every migration applied. Tests that need
+/// a database are ignored by default, run them with `cargo test -- --ignored`.
+#[cfg(test)]
+pub fn test_client() -> Client {
+    let mut client = connect_test_database();
+    crate::migrations::run(&mut client).expect("Couldn't migrate test database");
+    client
+}
+
+/// Client for the database at `TEST_DATABASE_URL` as it is.
+#[cfg(test)]
+pub fn connect_test_database() -> Client {
+    let url = std::env::var("TEST_DATABASE_URL")
+        .expect("TEST_DATABASE_URL must be set to run database tests");
+
+    connection_config(&url)
+        .and_then(|connection_config| {
+            connection_config
+                .connect(NoTls)
+                .map_err(|err| err.to_string())
+        })
+        .expect("Couldn't connect to test database")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore]
+    fn connections_use_utc() {
+        let mut client = test_client();
+
+        let timezone: String = client.query_one("show timezone", &[]).unwrap().get(0);
+        assert_eq!(timezone, "UTC");
+    }
+}
@@ -0,0 +1,132 @@
+use crate::models::error::ApiError;
+use crate::models::session::Session;
+use crate::models::user::User;
+use chrono::NaiveDateTime;
+use postgres::Transaction;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceToken {
+    pub token: String,
+
+    pub platform: Option<String>,
+
+    #[serde(rename = "appVersion")]
+    pub app_version: Option<String>,
+
+    #[serde(rename = "sessionId")]
+    #[serde(skip_deserializing)]
+    pub session_id: Option<i64>,
+
+    #[serde(rename = "createdAt")]
+    #[serde(skip_deserializing)]
+    pub created_at: Option<NaiveDateTime>,
+
+    #[serde(rename = "updatedAt")]
+    #[serde(skip_deserializing)]
+    pub updated_at: Option<NaiveDateTime>,
+}
+
+#[macro_export]
+macro_rules! device_token {
+    ($row:expr) => {
+        DeviceToken {
+            token: $row.get("token"),
+            platform: $row.get("platform"),
+            app_version: $row.get("app_version"),
+            session_id: $row.get("session_id"),
+            created_at: $row.get("created_at"),
+            updated_at: $row.get("updated_at"),
+        }
+    };
+}
+
+impl DeviceToken {
+    /// Registers this device for the user, moving the token over if another account used it.
+    pub fn register(
+        &self,
+        user: &User,
+        session: &Session,
+        transaction: &mut Transaction,
+    ) -> Result<Self, ApiError> {
+        match transaction.query_one(
+            "insert into firebase_device_tokens (
+                user_id,
+                token,
+                session_id,
+                platform,
+                app_version,
+                updated_at
+            ) values ($1, $2, $3, $4, $5, now())
+            on conflict (token) do update set
+                user_id = excluded.user_id,
+                session_id = excluded.session_id,
+                platform = excluded.platform,
+                app_version = excluded.app_version,
+                updated_at = now()
+            returning *
+            ",
+            &[
+                &user.id,
+                &self.token,
+                &session.id,
+                &self.platform,
+                &self.app_version,
+            ],
+        ) {
+            Ok(row) => Ok(device_token!(row)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn deregister(
+        token: &str,
+        user: &User,
+        transaction: &mut Transaction,
+    ) -> Result<Self, ApiError> {
+        match transaction.query_opt(
+            "delete from firebase_device_tokens
+            where token = $1
+                and user_id = $2
+            returning *
+            ",
+            &[&token, &user.id],
+        ) {
+            Ok(Some(row)) => Ok(device_token!(row)),
+            Ok(None) => Err(ApiError::NotFound(String::from(
+                "Device token is not registered",
+            ))),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn get_for_user(user: &User, transaction: &mut Transaction) -> Vec<Self> {
+        match transaction.query(
+            "select * from firebase_device_tokens where user_id = $1
+            order by updated_at desc
+            ",
+            &[&user.id],
+        ) {
+            Ok(rows) => rows
+                .iter()
+                .map(|row| device_token!(row))
+                .collect::<Vec<DeviceToken>>(),
+            Err(err) => {
+                error!("{}", err);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Drops a token FCM reported as no longer registered.
+    pub fn remove(token: &str, transaction: &mut Transaction) -> Result<u64, ApiError> {
+        match transaction.execute(
+            "delete from firebase_device_tokens where token = $1
+            ",
+            &[&token],
+        ) {
+            Ok(count) => Ok(count),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use rocket::http::Status;
+use std::fmt;
+
+/// Error returned by model methods and turned into a JSON response by the views.
+///
+/// Every variant maps to a single HTTP status and a stable `code` clients can match on.
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    PreconditionRequired(String),
+    Validation { field: String, message: String },
+    Upstream { service: String, message: String },
+    Database(String),
+    Internal(String),
+    Unavailable(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> Status {
+        match self {
+            ApiError::BadRequest(_) => Status::BadRequest,
+            ApiError::Unauthorized(_) => Status::Unauthorized,
+            ApiError::Forbidden(_) => Status::Forbidden,
+            ApiError::NotFound(_) => Status::NotFound,
+            ApiError::Conflict(_) => Status::Conflict,
+            ApiError::PreconditionRequired(_) => Status::PreconditionRequired,
+            ApiError::Validation { .. } => Status::UnprocessableEntity,
+            ApiError::Upstream { .. } => Status::BadGateway,
+            ApiError::Database(_) | ApiError::Internal(_) => Status::InternalServerError,
+            ApiError::Unavailable(_) => Status::ServiceUnavailable,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::PreconditionRequired(_) => "precondition_required",
+            ApiError::Validation { .. } => "validation_failed",
+            ApiError::Upstream { .. } => "upstream_error",
+            ApiError::Database(_) => "database_error",
+            ApiError::Internal(_) => "internal_error",
+            ApiError::Unavailable(_) => "service_unavailable",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::PreconditionRequired(message)
+            | ApiError::Validation { message, .. }
+            | ApiError::Upstream { message, .. }
+            | ApiError::Database(message)
+            | ApiError::Internal(message)
+            | ApiError::Unavailable(message) => message,
+        }
+    }
+
+    pub fn validation(field: &str, message: &str) -> Self {
+        ApiError::Validation {
+            field: String::from(field),
+            message: String::from(message),
+        }
+    }
+
+    pub fn upstream(service: &str, message: &str) -> Self {
+        ApiError::Upstream {
+            service: String::from(service),
+            message: String::from(message),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+impl From<postgres::Error> for ApiError {
+    fn from(err: postgres::Error) -> Self {
+        error!("{}", err);
+        ApiError::Database(String::from("A database error occurred"))
+    }
+}
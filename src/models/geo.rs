@@ -0,0 +1,332 @@
+const MILES_PER_KILOMETER: f64 = 0.621_371;
+const MILES_PER_DEGREE_LATITUDE: f64 = 69.0;
+const METERS_PER_DEGREE_LATITUDE: f64 = 111_320.0;
+const EARTH_RADIUS_MILES: f64 = 3958.8;
+pub const METERS_PER_MILE: f64 = 1609.344;
+const BLOCK_METERS: f64 = 150.0;
+const NEIGHBOURHOOD_METERS: f64 = 1000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DistanceUnit {
+    Miles,
+    Kilometers,
+}
+
+impl DistanceUnit {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "mi" | "miles" => Some(DistanceUnit::Miles),
+            "km" | "kilometers" => Some(DistanceUnit::Kilometers),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DistanceUnit::Miles => "miles",
+            DistanceUnit::Kilometers => "kilometers",
+        }
+    }
+
+    pub fn abbreviation(self) -> &'static str {
+        match self {
+            DistanceUnit::Miles => "mi",
+            DistanceUnit::Kilometers => "km",
+        }
+    }
+
+    pub fn to_miles(self, distance: f64) -> f64 {
+        match self {
+            DistanceUnit::Miles => distance,
+            DistanceUnit::Kilometers => distance * MILES_PER_KILOMETER,
+        }
+    }
+
+    pub fn convert_miles(self, distance: f64) -> f64 {
+        match self {
+            DistanceUnit::Miles => distance,
+            DistanceUnit::Kilometers => distance / MILES_PER_KILOMETER,
+        }
+    }
+}
+
+/// How precisely an alert's location is published.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LocationPrecision {
+    Exact,
+    Block,
+    Neighbourhood,
+}
+
+impl LocationPrecision {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "exact" => Some(LocationPrecision::Exact),
+            "block" => Some(LocationPrecision::Block),
+            "neighbourhood" => Some(LocationPrecision::Neighbourhood),
+            _ => None,
+        }
+    }
+
+    /// Size in meters of the grid cells the location is snapped to, `None` when exact.
+    pub fn grid_meters(self) -> Option<f64> {
+        match self {
+            LocationPrecision::Exact => None,
+            LocationPrecision::Block => Some(BLOCK_METERS),
+            LocationPrecision::Neighbourhood => Some(NEIGHBOURHOOD_METERS),
+        }
+    }
+}
+
+/// Latitude/longitude box enclosing a circle, used to prefilter rows with the location indexes
+/// before the exact great-circle distance is checked.
+#[derive(Debug, Clone, Copy)]
+pub struct BoundingBox {
+    pub min_latitude: f64,
+    pub max_latitude: f64,
+    pub min_longitude: f64,
+    pub max_longitude: f64,
+}
+
+impl BoundingBox {
+    pub fn around(latitude: f64, longitude: f64, radius_miles: f64) -> Self {
+        let latitude_delta = radius_miles / MILES_PER_DEGREE_LATITUDE;
+        let min_latitude = (latitude - latitude_delta).max(-90.0);
+        let max_latitude = (latitude + latitude_delta).min(90.0);
+
+        // Degrees of longitude shrink towards the poles, use the widest point of the circle
+        let widest = min_latitude
+            .abs()
+            .max(max_latitude.abs())
+            .to_radians()
+            .cos();
+        let longitude_delta = radius_miles / (MILES_PER_DEGREE_LATITUDE * widest);
+
+        // Near the poles or across the antimeridian every longitude has to be considered
+        if max_latitude >= 90.0
+            || min_latitude <= -90.0
+            || longitude - longitude_delta < -180.0
+            || longitude + longitude_delta > 180.0
+        {
+            return BoundingBox {
+                min_latitude,
+                max_latitude,
+                min_longitude: -180.0,
+                max_longitude: 180.0,
+            };
+        }
+
+        BoundingBox {
+            min_latitude,
+            max_latitude,
+            min_longitude: longitude - longitude_delta,
+            max_longitude: longitude + longitude_delta,
+        }
+    }
+}
+
+pub fn is_valid_coordinate(latitude: f64, longitude: f64) -> bool {
+    (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude)
+}
+
+/// Haversine distance in miles, the same as the great_circle_distance database function.
+pub fn great_circle_distance(
+    latitude1: f64,
+    longitude1: f64,
+    latitude2: f64,
+    longitude2: f64,
+) -> f64 {
+    let half_chord = ((latitude2 - latitude1).to_radians() / 2.0).sin().powi(2)
+        + latitude1.to_radians().cos()
+            * latitude2.to_radians().cos()
+            * ((longitude2 - longitude1).to_radians() / 2.0).sin().powi(2);
+
+    2.0 * EARTH_RADIUS_MILES * half_chord.sqrt().min(1.0).asin()
+}
+
+/// Indexes of the points to keep when simplifying a path of (latitude, longitude) pairs with
+/// Douglas-Peucker. Points closer than `tolerance_meters` to the simplified path are dropped,
+/// the first and last point are always kept.
+pub fn simplify_path(points: &[(f64, f64)], tolerance_meters: f64) -> Vec<usize> {
+    if points.len() < 3 {
+        return (0..points.len()).collect();
+    }
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    // Iterative, long trails would overflow the stack when recursing
+    let mut ranges = vec![(0, points.len() - 1)];
+    while let Some((start, end)) = ranges.pop() {
+        let mut farthest = None;
+        let mut max_distance = tolerance_meters;
+
+        for (index, point) in points.iter().enumerate().take(end).skip(start + 1) {
+            let distance = distance_to_segment(*point, points[start], points[end]);
+            if distance > max_distance {
+                max_distance = distance;
+                farthest = Some(index);
+            }
+        }
+
+        if let Some(index) = farthest {
+            keep[index] = true;
+            ranges.push((start, index));
+            ranges.push((index, end));
+        }
+    }
+
+    keep.iter()
+        .enumerate()
+        .filter(|(_, keep)| **keep)
+        .map(|(index, _)| index)
+        .collect()
+}
+
+// Distance in meters, on a flat projection around the segment's start which is accurate enough
+// over the few miles a trail spans
+fn distance_to_segment(point: (f64, f64), start: (f64, f64), end: (f64, f64)) -> f64 {
+    let longitude_scale = start.0.to_radians().cos();
+    let project = |(latitude, longitude): (f64, f64)| {
+        (
+            (longitude - start.1) * longitude_scale * METERS_PER_DEGREE_LATITUDE,
+            (latitude - start.0) * METERS_PER_DEGREE_LATITUDE,
+        )
+    };
+
+    let (x, y) = project(point);
+    let (end_x, end_y) = project(end);
+
+    let length_squared = end_x * end_x + end_y * end_y;
+    let along = if length_squared > 0.0 {
+        ((x * end_x + y * end_y) / length_squared).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+
+    ((x - along * end_x).powi(2) + (y - along * end_y).powi(2)).sqrt()
+}
+
+/// Center of the roughly square grid cell, `cell_meters` wide, that contains the point. Every
+/// point in a cell snaps to the same center, so it gives away nothing finer than the cell.
+pub fn snap_to_grid(latitude: f64, longitude: f64, cell_meters: f64) -> (f64, f64) {
+    let latitude_step = cell_meters / METERS_PER_DEGREE_LATITUDE;
+    let latitude = (((latitude / latitude_step).floor() + 0.5) * latitude_step).clamp(-90.0, 90.0);
+
+    // Cells are as wide in meters as they are tall, using the snapped latitude keeps every
+    // point of a cell on the same longitude steps
+    let longitude_step =
+        cell_meters / (METERS_PER_DEGREE_LATITUDE * latitude.to_radians().cos().max(0.01));
+    let longitude =
+        (((longitude / longitude_step).floor() + 0.5) * longitude_step).clamp(-180.0, 180.0);
+
+    (latitude, longitude)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounding_box_surrounds_the_circle() {
+        let bounds = BoundingBox::around(40.0, -75.0, 10.0);
+
+        assert!((bounds.max_latitude - bounds.min_latitude - 20.0 / 69.0).abs() < 1e-9);
+        // A degree of longitude is shorter than a degree of latitude away from the equator
+        assert!(bounds.max_longitude - bounds.min_longitude > 20.0 / 69.0);
+        assert!(bounds.min_longitude < -75.0 && bounds.max_longitude > -75.0);
+    }
+
+    #[test]
+    fn bounding_box_spans_every_longitude_near_the_poles() {
+        for latitude in &[89.95, -89.95] {
+            let bounds = BoundingBox::around(*latitude, 10.0, 5.0);
+
+            assert!(bounds.min_latitude >= -90.0 && bounds.max_latitude <= 90.0);
+            assert_eq!(
+                (bounds.min_longitude, bounds.max_longitude),
+                (-180.0, 180.0)
+            );
+        }
+    }
+
+    #[test]
+    fn bounding_box_spans_every_longitude_across_the_antimeridian() {
+        for longitude in &[179.95, -179.95] {
+            let bounds = BoundingBox::around(0.0, *longitude, 5.0);
+
+            assert!((bounds.max_latitude - bounds.min_latitude - 10.0 / 69.0).abs() < 1e-9);
+            assert_eq!(
+                (bounds.min_longitude, bounds.max_longitude),
+                (-180.0, 180.0)
+            );
+        }
+    }
+
+    #[test]
+    fn great_circle_distance_between_cities() {
+        // New York City to Los Angeles
+        let distance = great_circle_distance(40.7128, -74.0060, 34.0522, -118.2437);
+        assert!((distance - 2445.0).abs() < 5.0, "{}", distance);
+
+        assert_eq!(
+            great_circle_distance(40.7128, -74.0060, 40.7128, -74.0060),
+            0.0
+        );
+        assert!((great_circle_distance(0.0, 179.5, 0.0, -179.5) - 69.1).abs() < 0.1);
+    }
+
+    #[test]
+    fn simplify_path_drops_points_within_tolerance() {
+        // About 11 meters off a straight line heading north
+        let points = [(0.0, 0.0), (0.01, 0.0001), (0.02, -0.0001), (0.03, 0.0)];
+
+        assert_eq!(simplify_path(&points, 20.0), vec![0, 3]);
+        assert_eq!(simplify_path(&points, 5.0), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn simplify_path_keeps_corners_and_endpoints() {
+        // North, then a corner, then east, with a point back near the start
+        let points = [
+            (0.0, 0.0),
+            (0.005, 0.00001),
+            (0.01, 0.0),
+            (0.01, 0.005),
+            (0.01, 0.01),
+            (0.00001, 0.0),
+        ];
+
+        assert_eq!(simplify_path(&points, 10.0), vec![0, 2, 4, 5]);
+        assert_eq!(simplify_path(&points[..2], 1000.0), vec![0, 1]);
+        assert!(simplify_path(&[], 10.0).is_empty());
+    }
+
+    #[test]
+    fn snap_to_grid_maps_a_cell_to_its_center() {
+        let center = snap_to_grid(40.7128, -74.0060, 1000.0);
+
+        // Nearby points in the same cell share its center, which is within half a diagonal
+        assert_eq!(snap_to_grid(40.7129, -74.0061, 1000.0), center);
+        assert_eq!(snap_to_grid(center.0, center.1, 1000.0), center);
+        let offset = great_circle_distance(40.7128, -74.0060, center.0, center.1);
+        assert!(
+            offset * METERS_PER_MILE <= 1000.0 / 2.0_f64.sqrt(),
+            "{}",
+            offset
+        );
+
+        // A point a cell away snaps elsewhere
+        assert_ne!(snap_to_grid(40.7228, -74.0060, 1000.0), center);
+    }
+
+    #[test]
+    fn snap_to_grid_stays_on_the_globe() {
+        let (latitude, longitude) = snap_to_grid(90.0, 180.0, 1000.0);
+        assert!(is_valid_coordinate(latitude, longitude));
+
+        let (latitude, longitude) = snap_to_grid(-90.0, -180.0, 1000.0);
+        assert!(is_valid_coordinate(latitude, longitude));
+    }
+}
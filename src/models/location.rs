@@ -1,9 +1,52 @@
-use crate::models::alerts::Alert;
-use chrono::NaiveDateTime;
+use crate::config::Config;
+use crate::models::alerts::{Alert, TrackingPlace};
+use crate::models::error::ApiError;
+use crate::models::geo::{
+    great_circle_distance, is_valid_coordinate, snap_to_grid, METERS_PER_MILE,
+};
+use crate::models::notification::OutboxNotification;
+use crate::models::privacy::PrivacySettings;
+use crate::models::track::TrackPoint;
+use crate::models::zone::WatchZone;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
 use postgres::Transaction;
-use serde::{Deserialize, Serialize};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+// Device clocks drift, points from slightly in the future are still accepted
+const MAX_CLOCK_SKEW_MINUTES: i64 = 5;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DeviceTime {
+    // Milliseconds since the epoch, as the platform location APIs report it
+    Millis(i64),
+    Text(String),
+}
+
+/// Reads a device timestamp given either as RFC 3339 or as epoch milliseconds.
+fn device_time<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<NaiveDateTime>, D::Error> {
+    match Option::<DeviceTime>::deserialize(deserializer)? {
+        Some(DeviceTime::Millis(millis)) => NaiveDateTime::from_timestamp_opt(
+            millis.div_euclid(1000),
+            (millis.rem_euclid(1000) * 1_000_000) as u32,
+        )
+        .map(Some)
+        .ok_or_else(|| D::Error::custom("recordedAt is out of range")),
+        Some(DeviceTime::Text(text)) => DateTime::parse_from_rfc3339(&text)
+            .map(|time| time.naive_utc())
+            .or_else(|_| NaiveDateTime::parse_from_str(&text, "%Y-%m-%dT%H:%M:%S%.f"))
+            .map(Some)
+            .map_err(|_| {
+                D::Error::custom("recordedAt must be an RFC 3339 timestamp or epoch milliseconds")
+            }),
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Location {
     #[serde(rename = "userId")]
     #[serde(skip_deserializing)]
@@ -13,6 +56,27 @@ pub struct Location {
 
     pub longitude: f32,
 
+    // Device time of the point, the upload time when the app doesn't send one
+    #[serde(rename = "recordedAt", alias = "timestamp")]
+    #[serde(default, deserialize_with = "device_time")]
+    pub recorded_at: Option<NaiveDateTime>,
+
+    // Horizontal accuracy in meters
+    pub accuracy: Option<f32>,
+
+    // In meters above the WGS 84 ellipsoid
+    pub altitude: Option<f32>,
+
+    // In meters per second
+    pub speed: Option<f32>,
+
+    // In degrees from north
+    pub heading: Option<f32>,
+
+    // Whatever produced the point on the device, e.g. gps or network
+    #[serde(alias = "provider")]
+    pub source: Option<String>,
+
     #[serde(rename = "createdAt")]
     #[serde(skip_deserializing)]
     pub created_at: Option<NaiveDateTime>,
@@ -29,38 +93,548 @@ macro_rules! location {
             user_id: $row.get("user_id"),
             latitude: $row.get("latitude"),
             longitude: $row.get("longitude"),
+            recorded_at: $row.get("recorded_at"),
+            accuracy: $row.get("accuracy"),
+            altitude: $row.get("altitude"),
+            speed: $row.get("speed"),
+            heading: $row.get("heading"),
+            source: $row.get("source"),
             created_at: $row.get("created_at"),
             updated_at: $row.get("updated_at"),
         }
     };
 }
 
+/// A point of an uploaded batch that was left out, by its position in the batch.
+#[derive(Debug, Serialize)]
+pub struct RejectedLocation {
+    pub index: usize,
+    pub reason: String,
+}
+
+/// The outcome of storing an uploaded batch.
+#[derive(Debug)]
+pub struct LocationBatch {
+    // The user's current location after the batch
+    pub location: Location,
+    // How many points were new to the history
+    pub recorded: u64,
+    pub rejected: Vec<RejectedLocation>,
+}
+
 impl Location {
-    pub fn init_or_update(&self, transaction: &mut Transaction) -> Result<Self, String> {
-        match transaction.query_one(
-            "insert into locations (
+    /// Why the point can't be right on its own, if it can't.
+    fn problem(&self, uploaded_at: NaiveDateTime) -> Option<&'static str> {
+        if !is_valid_coordinate(f64::from(self.latitude), f64::from(self.longitude)) {
+            return Some("Coordinates are out of range");
+        }
+
+        if let Some(recorded_at) = self.recorded_at {
+            if recorded_at > uploaded_at + Duration::minutes(MAX_CLOCK_SKEW_MINUTES) {
+                return Some("recordedAt is in the future");
+            }
+        }
+
+        if self.accuracy.map_or(false, |accuracy| accuracy < 0.0) {
+            return Some("accuracy must not be negative");
+        }
+
+        if self.speed.map_or(false, |speed| speed < 0.0) {
+            return Some("speed must not be negative");
+        }
+
+        if self
+            .heading
+            .map_or(false, |heading| !(0.0..=360.0).contains(&heading))
+        {
+            return Some("heading must be between 0 and 360");
+        }
+
+        None
+    }
+
+    /// Whether the user could have gone from `previous` to this point in the time between
+    /// them. Both points' accuracy is given the benefit of the doubt.
+    fn is_reachable_from(&self, previous: &Location, max_speed_mps: f64) -> bool {
+        let seconds = match (previous.recorded_at, self.recorded_at) {
+            (Some(from), Some(to)) if to > from => (to - from).num_milliseconds() as f64 / 1000.0,
+            _ => return true,
+        };
+
+        let meters = great_circle_distance(
+            f64::from(previous.latitude),
+            f64::from(previous.longitude),
+            f64::from(self.latitude),
+            f64::from(self.longitude),
+        ) * METERS_PER_MILE
+            - f64::from(previous.accuracy.unwrap_or(0.0))
+            - f64::from(self.accuracy.unwrap_or(0.0));
+
+        meters / seconds <= max_speed_mps
+    }
+
+    /// The point snapped to a privacy grid `grid` meters wide, or as is without one. Its
+    /// accuracy is widened to the cell and the altitude is left out.
+    pub fn snapped(&self, grid: Option<f64>) -> Self {
+        let cell_meters = match grid {
+            Some(cell_meters) => cell_meters,
+            None => return self.clone(),
+        };
+
+        let (latitude, longitude) = snap_to_grid(
+            f64::from(self.latitude),
+            f64::from(self.longitude),
+            cell_meters,
+        );
+        let cell_accuracy = (cell_meters * std::f64::consts::FRAC_1_SQRT_2) as f32;
+
+        Location {
+            latitude: latitude as f32,
+            longitude: longitude as f32,
+            accuracy: Some(
+                self.accuracy
+                    .map_or(cell_accuracy, |a| a.max(cell_accuracy)),
+            ),
+            altitude: None,
+            ..self.clone()
+        }
+    }
+
+    fn get_current(user_id: i64, transaction: &mut Transaction) -> Result<Option<Self>, ApiError> {
+        match transaction.query_opt(
+            "select * from locations where user_id = $1
+            ",
+            &[&user_id],
+        ) {
+            Ok(row) => Ok(row.map(|row| location!(row))),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Stores a batch of uploaded points in the user's location history and makes the latest
+    /// one their current location. Points are ordered by device time, points the history
+    /// already has are skipped and obviously wrong points are rejected, including ones too
+    /// far from the point before them to have been reached in time.
+    pub fn record_batch(
+        user_id: i64,
+        locations: Vec<Self>,
+        place: Option<&TrackingPlace>,
+        config: &Config,
+        transaction: &mut Transaction,
+    ) -> Result<LocationBatch, ApiError> {
+        let uploaded_at = Utc::now().naive_utc();
+        let mut rejected = Vec::new();
+        let mut points = Vec::new();
+
+        for (index, mut location) in locations.into_iter().enumerate() {
+            location.user_id = user_id;
+            location.recorded_at = location.recorded_at.or(Some(uploaded_at));
+
+            match location.problem(uploaded_at) {
+                Some(reason) => rejected.push(RejectedLocation {
+                    index,
+                    reason: String::from(reason),
+                }),
+                None => points.push((index, location)),
+            }
+        }
+
+        // Stable sort, so of the points sharing a time the last uploaded one is kept
+        points.sort_by_key(|(_, location)| location.recorded_at);
+        points.reverse();
+        points.dedup_by_key(|(_, location)| location.recorded_at);
+        points.reverse();
+
+        let mut locations: Vec<Self> = Vec::new();
+        let current = Self::get_current(user_id, transaction)?;
+
+        for (index, location) in points {
+            let reachable = match locations.last().or(current.as_ref()) {
+                Some(last) => location.is_reachable_from(last, config.locations.max_speed_mps),
+                None => true,
+            };
+
+            if reachable {
+                locations.push(location);
+            } else {
+                rejected.push(RejectedLocation {
+                    index,
+                    reason: String::from(
+                        "Too far from the previous location to be reached in time",
+                    ),
+                });
+            }
+        }
+
+        rejected.sort_by_key(|rejected| rejected.index);
+
+        if locations.is_empty() {
+            let reason = rejected
+                .first()
+                .map_or("No new locations given", |rejected| {
+                    rejected.reason.as_str()
+                });
+            return Err(ApiError::validation("locations", reason));
+        }
+
+        // History and trails are seen by others, so they only get what the user shares
+        let grid =
+            PrivacySettings::get_for_user(user_id, transaction).location_grid(&config.locations);
+        let shared = locations
+            .iter()
+            .map(|location| location.snapped(grid))
+            .collect::<Vec<Location>>();
+
+        let recorded = match transaction.execute(
+            "insert into location_history (
+                user_id,
+                latitude,
+                longitude,
+                recorded_at,
+                accuracy,
+                altitude,
+                speed,
+                heading,
+                source
+            )
+            select
+                $1, p.latitude, p.longitude, p.recorded_at,
+                p.accuracy, p.altitude, p.speed, p.heading, p.source
+            from unnest(
+                $2::real[], $3::real[], $4::timestamp[],
+                $5::real[], $6::real[], $7::real[], $8::real[], $9::text[]
+            ) as p(latitude, longitude, recorded_at, accuracy, altitude, speed, heading, source)
+            on conflict (user_id, recorded_at) do nothing
+            ",
+            &[
+                &user_id,
+                &shared.iter().map(|l| l.latitude).collect::<Vec<f32>>(),
+                &shared.iter().map(|l| l.longitude).collect::<Vec<f32>>(),
+                &shared
+                    .iter()
+                    .map(|l| l.recorded_at)
+                    .collect::<Vec<Option<NaiveDateTime>>>(),
+                &shared
+                    .iter()
+                    .map(|l| l.accuracy)
+                    .collect::<Vec<Option<f32>>>(),
+                &shared
+                    .iter()
+                    .map(|l| l.altitude)
+                    .collect::<Vec<Option<f32>>>(),
+                &shared.iter().map(|l| l.speed).collect::<Vec<Option<f32>>>(),
+                &shared
+                    .iter()
+                    .map(|l| l.heading)
+                    .collect::<Vec<Option<f32>>>(),
+                &shared
+                    .iter()
+                    .map(|l| l.source.clone())
+                    .collect::<Vec<Option<String>>>(),
+            ],
+        ) {
+            Ok(count) => count,
+            Err(err) => return Err(err.into()),
+        };
+
+        // Tracked alerts get every point, not just the latest they are moved to
+        TrackPoint::record_for_user(user_id, &shared, transaction)?;
+
+        match locations.last() {
+            Some(latest) => Ok(LocationBatch {
+                location: latest.init_or_update(grid, place, config, transaction)?,
+                recorded,
+                rejected,
+            }),
+            None => Err(ApiError::validation("locations", "No new locations given")),
+        }
+    }
+
+    /// Makes this the user's current location unless they already have a more recent one, in
+    /// which case that one is returned unchanged. Alerts target the user at their latest point
+    /// accurate enough for it, kept exact, tracked alerts follow the current one snapped to the
+    /// user's privacy `grid` and are shown at `place` if it was looked up for that point.
+    pub fn init_or_update(
+        &self,
+        grid: Option<f64>,
+        place: Option<&TrackingPlace>,
+        config: &Config,
+        transaction: &mut Transaction,
+    ) -> Result<Self, ApiError> {
+        match transaction.query_opt(
+            "with previous as (
+                select latitude, longitude from locations where user_id = $1
+            )
+            insert into locations (
                 user_id,
                 latitude,
-                longitude
-            ) values ($1, $2, $3)
+                longitude,
+                recorded_at,
+                accuracy,
+                altitude,
+                speed,
+                heading,
+                source,
+                targeting_latitude,
+                targeting_longitude,
+                targeting_accuracy
+            ) values (
+                $1, $2, $3, coalesce($4::timestamp, now()::timestamp), $5, $6, $7, $8, $9,
+                case when $5::real is null or $5::real <= $10::real then $2::real end,
+                case when $5::real is null or $5::real <= $10::real then $3::real end,
+                case when $5::real is null or $5::real <= $10::real then $5::real end
+            )
             on conflict (user_id) do update
             set
                 latitude = excluded.latitude,
                 longitude = excluded.longitude,
+                targeting_latitude =
+                    coalesce(excluded.targeting_latitude, locations.targeting_latitude),
+                targeting_longitude =
+                    coalesce(excluded.targeting_longitude, locations.targeting_longitude),
+                targeting_accuracy = case
+                    when excluded.targeting_latitude is null then locations.targeting_accuracy
+                    else excluded.targeting_accuracy
+                end,
+                recorded_at = excluded.recorded_at,
+                accuracy = excluded.accuracy,
+                altitude = excluded.altitude,
+                speed = excluded.speed,
+                heading = excluded.heading,
+                source = excluded.source,
                 updated_at = now()
-            returning *
+            where locations.recorded_at is null
+                or locations.recorded_at < excluded.recorded_at
+            returning
+                *,
+                (select latitude from previous) as previous_latitude,
+                (select longitude from previous) as previous_longitude
             ",
-            &[&self.user_id, &self.latitude, &self.longitude],
+            &[
+                &self.user_id,
+                &self.latitude,
+                &self.longitude,
+                &self.recorded_at,
+                &self.accuracy,
+                &self.altitude,
+                &self.speed,
+                &self.heading,
+                &self.source,
+                &config.locations.max_targeting_accuracy_meters,
+            ],
         ) {
-            Ok(row) => {
+            Ok(Some(row)) => {
                 let location = location!(row);
-                Alert::update_tracking_alert(&location, transaction);
+
+                // Tracked alerts follow their creator, notify anyone they now reach
+                let shared = location.snapped(grid);
+                for alert in Alert::update_tracking_alert(&shared, place, transaction) {
+                    OutboxNotification::enqueue_for_new_recipients(&alert, config, transaction)?;
+                }
+
+                // Zones are the user's own, so they see transitions at their exact location
+                let previous: (Option<f32>, Option<f32>) =
+                    (row.get("previous_latitude"), row.get("previous_longitude"));
+                if let (Some(previous_latitude), Some(previous_longitude)) = previous {
+                    for (zone, entered) in WatchZone::crossed(
+                        location.user_id,
+                        (previous_latitude, previous_longitude),
+                        (location.latitude, location.longitude),
+                        transaction,
+                    )? {
+                        OutboxNotification::enqueue_zone_transition(
+                            &zone,
+                            entered,
+                            config,
+                            transaction,
+                        )?;
+                    }
+                }
+
                 Ok(location)
             }
-            Err(err) => {
-                error!("{}", err);
-                Err(String::from("Couldn't update location information"))
-            }
+            Ok(None) => match Self::get_current(self.user_id, transaction)? {
+                Some(location) => Ok(location),
+                None => Err(ApiError::NotFound(String::from("User location not found"))),
+            },
+            Err(err) => Err(err.into()),
         }
     }
+
+    /// Deletes history points recorded longer ago than the retention period.
+    pub fn prune_history(
+        retention_days: i32,
+        transaction: &mut Transaction,
+    ) -> Result<u64, ApiError> {
+        match transaction.execute(
+            "delete from location_history
+            where recorded_at < now() - make_interval(days => $1)
+            ",
+            &[&retention_days],
+        ) {
+            Ok(count) => Ok(count),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FirebaseConfig;
+    use crate::models::alerts::AlertType;
+    use crate::models::database::test_client;
+
+    fn point(latitude: f32, recorded_at: Option<NaiveDateTime>) -> Location {
+        Location {
+            user_id: -201,
+            latitude,
+            longitude: -75.0,
+            recorded_at,
+            accuracy: None,
+            altitude: None,
+            speed: None,
+            heading: None,
+            source: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn init_or_update_keeps_the_newest_point() {
+        let mut client = test_client();
+        let mut transaction = client.transaction().unwrap();
+        transaction
+            .batch_execute(
+                "insert into users (id, name, email, password, phone)
+                values (-201, 'Walker', 'walker@locations.test', 'x', '1');
+                ",
+            )
+            .unwrap();
+
+        let config = Config::for_tests();
+        let now = Utc::now().naive_utc();
+
+        // Points without a device time are recorded at upload time
+        let location = point(40.0, None)
+            .init_or_update(None, None, &config, &mut transaction)
+            .unwrap();
+        assert_eq!(location.latitude, 40.0);
+        assert!(location.recorded_at.is_some());
+
+        let stale = point(41.0, Some(now - Duration::hours(1)))
+            .init_or_update(None, None, &config, &mut transaction)
+            .unwrap();
+        assert_eq!(stale.latitude, 40.0);
+
+        let newer = point(42.0, Some(now + Duration::minutes(1)))
+            .init_or_update(None, None, &config, &mut transaction)
+            .unwrap();
+        assert_eq!(newer.latitude, 42.0);
+    }
+
+    #[test]
+    #[ignore]
+    fn alerts_target_the_last_accurate_point() {
+        let mut client = test_client();
+        let mut transaction = client.transaction().unwrap();
+        transaction
+            .batch_execute(
+                "insert into users (id, name, email, password, phone) values
+                    (-221, 'Indoors', 'indoors@locations.test', 'x', '1'),
+                    (-222, 'Reporter', 'reporter@locations.test', 'x', '2');
+                insert into firebase_device_tokens (user_id, token) values (-221, 'indoors');
+                ",
+            )
+            .unwrap();
+
+        let config = Config::for_tests();
+        let now = Utc::now().naive_utc();
+        let fix = |latitude: f32, accuracy: f32, minutes: i64| Location {
+            user_id: -221,
+            accuracy: Some(accuracy),
+            ..point(latitude, Some(now + Duration::minutes(minutes)))
+        };
+
+        fix(40.0, 20.0, 0)
+            .init_or_update(None, None, &config, &mut transaction)
+            .unwrap();
+        let current = fix(40.3, 3000.0, 1)
+            .init_or_update(None, None, &config, &mut transaction)
+            .unwrap();
+        assert_eq!(current.latitude, 40.3);
+
+        let id: i64 = transaction
+            .query_one(
+                "insert into alerts (alert_type, place, latitude, longitude, created_by)
+                values ('Theft', 'Main Street', 40, -75, 'reporter@locations.test')
+                returning id
+                ",
+                &[],
+            )
+            .unwrap()
+            .get("id");
+        let alert = Alert::get_by_id(id, &mut transaction).unwrap();
+        let alert_type = AlertType::get_by_name(&alert.alert_type, &mut transaction).unwrap();
+
+        let recipients = alert
+            .record_recipients(&alert_type, &config.locations, &mut transaction)
+            .unwrap();
+        assert_eq!(recipients.len(), 1);
+        assert_eq!(recipients[0].token, "indoors");
+        assert_eq!(recipients[0].distance, Some(0.0));
+    }
+
+    #[test]
+    #[ignore]
+    fn tracked_alerts_follow_their_creator_without_geocoding() {
+        let mut client = test_client();
+        let mut transaction = client.transaction().unwrap();
+        transaction
+            .batch_execute(
+                "insert into users (id, name, email, password, phone) values
+                    (-211, 'Tracker', 'tracker@locations.test', 'x', '1'),
+                    (-212, 'Bystander', 'bystander@locations.test', 'x', '2');
+                insert into locations (
+                    user_id, latitude, longitude, targeting_latitude, targeting_longitude
+                ) values (-211, 41, -75, 41, -75), (-212, 42, -75, 42, -75);
+                insert into firebase_device_tokens (user_id, token) values (-212, 'bystander');
+                insert into alerts (alert_type, place, latitude, longitude, created_by, track_location)
+                values ('Theft', 'Main Street', 41, -75, 'tracker@locations.test', true);
+                ",
+            )
+            .unwrap();
+
+        let mut config = Config::for_tests();
+        config.firebase = Some(FirebaseConfig::for_tests("http://localhost"));
+
+        Location {
+            user_id: -211,
+            ..point(42.01, None)
+        }
+        .init_or_update(None, None, &config, &mut transaction)
+        .unwrap();
+
+        let alert = transaction
+            .query_one(
+                "select latitude, place from alerts where created_by = 'tracker@locations.test'
+                ",
+                &[],
+            )
+            .unwrap();
+        assert_eq!(alert.get::<_, f32>("latitude"), 42.01);
+        assert_eq!(alert.get::<_, String>("place"), "42.01000, -75.00000");
+
+        let queued: i64 = transaction
+            .query_one(
+                "select count(*) from notification_outbox where token = 'bystander'
+                ",
+                &[],
+            )
+            .unwrap()
+            .get(0);
+        assert_eq!(queued, 1);
+    }
 }
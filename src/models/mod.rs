@@ -1,5 +1,15 @@
 pub mod alerts;
 pub mod auth;
+pub mod block;
 pub mod database;
+pub mod device;
+pub mod error;
+pub mod geo;
 pub mod location;
+pub mod notification;
+pub mod preferences;
+pub mod privacy;
+pub mod session;
+pub mod track;
 pub mod user;
+pub mod zone;
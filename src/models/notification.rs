@@ -0,0 +1,535 @@
+use crate::config::{Config, NotificationConfig};
+use crate::models::alerts::{Alert, AlertType};
+use crate::models::error::ApiError;
+use crate::models::geo::DistanceUnit;
+use crate::models::zone::WatchZone;
+use chrono::Utc;
+use postgres::Transaction;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A push notification in the outbox, delivered to a single device by the outbox workers.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutboxNotification {
+    pub id: i64,
+
+    #[serde(rename = "alertId")]
+    pub alert_id: Option<i64>,
+
+    #[serde(rename = "userId")]
+    pub user_id: i64,
+
+    pub token: String,
+
+    pub title: String,
+
+    pub body: String,
+
+    pub data: Value,
+
+    pub attempts: i32,
+
+    #[serde(rename = "pushPriority")]
+    pub push_priority: String,
+
+    pub sound: Option<String>,
+
+    #[serde(rename = "androidChannel")]
+    pub android_channel: Option<String>,
+}
+
+#[macro_export]
+macro_rules! outbox_notification {
+    ($row:expr) => {
+        OutboxNotification {
+            id: $row.get("id"),
+            alert_id: $row.get("alert_id"),
+            user_id: $row.get("user_id"),
+            token: $row.get("token"),
+            title: $row.get("title"),
+            body: $row.get("body"),
+            data: $row.get("data"),
+            attempts: $row.get("attempts"),
+            push_priority: $row.get("push_priority"),
+            sound: $row.get("sound"),
+            android_channel: $row.get("android_channel"),
+        }
+    };
+}
+
+/// What happened to an alert, each with its own notification template.
+#[derive(Debug, Clone, Copy)]
+pub enum AlertEvent {
+    Created,
+    Updated,
+    Resolved,
+    // Resolved by the system once the alert's expiry passed
+    Expired,
+    // Resolved by the system once a planned event ended
+    Ended,
+    Retracted,
+}
+
+impl AlertEvent {
+    fn name(self) -> &'static str {
+        match self {
+            AlertEvent::Created => "created",
+            AlertEvent::Updated => "updated",
+            AlertEvent::Resolved => "resolved",
+            AlertEvent::Expired => "expired",
+            AlertEvent::Ended => "ended",
+            AlertEvent::Retracted => "retracted",
+        }
+    }
+
+    fn title(self, alert: &Alert) -> String {
+        match self {
+            AlertEvent::Created => format!("Elevate {} Alert", &alert.alert_type),
+            AlertEvent::Updated => format!("Elevate {} Alert Updated", &alert.alert_type),
+            AlertEvent::Resolved => format!("Elevate {} Alert Resolved", &alert.alert_type),
+            AlertEvent::Expired => format!("Elevate {} Alert Expired", &alert.alert_type),
+            AlertEvent::Ended => format!("Elevate {} Event Ended", &alert.alert_type),
+            AlertEvent::Retracted => format!("Elevate {} Alert Retracted", &alert.alert_type),
+        }
+    }
+
+    fn body(self, alert: &Alert) -> String {
+        let place = alert.place.as_deref().unwrap_or("you");
+        match self {
+            AlertEvent::Created if alert.starts_at > Some(Utc::now().naive_utc()) => {
+                format!("{} starting soon near {}!", &alert.alert_type, place)
+            }
+            AlertEvent::Created => format!("{} reported near {}!", &alert.alert_type, place),
+            AlertEvent::Updated => format!(
+                "The {} alert near {} has been updated",
+                &alert.alert_type, place
+            ),
+            AlertEvent::Resolved => format!(
+                "The {} alert near {} has been resolved",
+                &alert.alert_type, place
+            ),
+            AlertEvent::Expired => {
+                format!("The {} alert near {} has expired", &alert.alert_type, place)
+            }
+            AlertEvent::Ended => format!("The {} near {} has ended", &alert.alert_type, place),
+            AlertEvent::Retracted => format!(
+                "The {} alert near {} was retracted by its creator",
+                &alert.alert_type, place
+            ),
+        }
+    }
+
+    // FCM only accepts string values in the data payload
+    fn data(self, alert: &Alert) -> Value {
+        json!({
+            "alertId": alert.id.to_string(),
+            "event": self.name()
+        })
+        .0
+    }
+}
+
+/// The alert's type, which decides how far and how loudly it notifies.
+fn alert_type(alert: &Alert, transaction: &mut Transaction) -> Result<AlertType, ApiError> {
+    AlertType::get_by_name(&alert.alert_type, transaction).ok_or_else(|| {
+        ApiError::validation(
+            "alertType",
+            &format!("Unknown alert type {}", &alert.alert_type),
+        )
+    })
+}
+
+impl OutboxNotification {
+    /// Queues a notification for every device of the users newly in range of the alert.
+    pub fn enqueue_for_new_recipients(
+        alert: &Alert,
+        config: &Config,
+        transaction: &mut Transaction,
+    ) -> Result<Option<u64>, ApiError> {
+        if config.firebase.is_none() {
+            return Ok(None);
+        }
+
+        if !alert.is_active() || !alert.is_notification_due(&config.alerts) {
+            return Ok(Some(0));
+        }
+
+        let alert_type = alert_type(alert, transaction)?;
+        if !alert_type.notify {
+            return Ok(Some(0));
+        }
+
+        let notification_info =
+            alert.record_recipients(&alert_type, &config.locations, transaction)?;
+        if notification_info.is_empty() {
+            return Ok(Some(0));
+        }
+
+        let event = AlertEvent::Created;
+        let title = event.title(alert);
+        let data = event.data(alert);
+
+        let mut user_ids = Vec::new();
+        let mut tokens = Vec::new();
+        let mut bodies = Vec::new();
+
+        for info in notification_info {
+            let unit = DistanceUnit::parse(&info.distance_unit).unwrap_or(DistanceUnit::Miles);
+            bodies.push(match (info.distance, &info.zone) {
+                (Some(distance), _) => format!(
+                    "{} About {:.1} {} away",
+                    event.body(alert),
+                    unit.convert_miles(f64::from(distance)),
+                    unit.name()
+                ),
+                (None, Some(zone)) => format!("{} In your {} zone", event.body(alert), zone),
+                (None, None) => event.body(alert),
+            });
+            user_ids.push(info.user_id);
+            tokens.push(info.token);
+        }
+
+        match transaction.execute(
+            "insert into notification_outbox (
+                alert_id,
+                user_id,
+                token,
+                title,
+                body,
+                data,
+                push_priority,
+                sound,
+                android_channel
+            )
+            select $1, n.user_id, n.token, $2, n.body, $3, $7, $8, $9
+            from unnest($4::bigint[], $5::text[], $6::text[]) as n(user_id, token, body)
+            ",
+            &[
+                &alert.id,
+                &title,
+                &data,
+                &user_ids,
+                &tokens,
+                &bodies,
+                &alert_type.push_priority,
+                &alert_type.sound,
+                &alert_type.android_channel,
+            ],
+        ) {
+            Ok(count) => Ok(Some(count)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Queues a follow-up for every device of the alert's recipients.
+    pub fn enqueue_follow_up(
+        alert: &Alert,
+        event: AlertEvent,
+        config: &Config,
+        transaction: &mut Transaction,
+    ) -> Result<Option<u64>, ApiError> {
+        if config.firebase.is_none() {
+            return Ok(None);
+        }
+
+        let alert_type = alert_type(alert, transaction)?;
+
+        match transaction.execute(
+            "insert into notification_outbox (
+                alert_id,
+                user_id,
+                token,
+                title,
+                body,
+                data,
+                push_priority,
+                sound,
+                android_channel
+            )
+            select $1, ar.user_id, fdt.token, $2, $3, $4, $5, $6, $7
+            from alert_recipients ar
+            inner join firebase_device_tokens fdt
+                on ar.user_id = fdt.user_id
+            where ar.alert_id = $1
+            ",
+            &[
+                &alert.id,
+                &event.title(alert),
+                &event.body(alert),
+                &event.data(alert),
+                &alert_type.push_priority,
+                &alert_type.sound,
+                &alert_type.android_channel,
+            ],
+        ) {
+            Ok(count) => Ok(Some(count)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Tells the zone's owner they entered or left it.
+    pub fn enqueue_zone_transition(
+        zone: &WatchZone,
+        entered: bool,
+        config: &Config,
+        transaction: &mut Transaction,
+    ) -> Result<Option<u64>, ApiError> {
+        if config.firebase.is_none() {
+            return Ok(None);
+        }
+
+        let (event, title, body) = match entered {
+            true => (
+                "zone_entered",
+                format!("Entered {}", &zone.name),
+                format!("You arrived in your {} zone", &zone.name),
+            ),
+            false => (
+                "zone_exited",
+                format!("Left {}", &zone.name),
+                format!("You left your {} zone", &zone.name),
+            ),
+        };
+
+        let data = json!({
+            "zoneId": zone.id.to_string(),
+            "event": event
+        })
+        .0;
+
+        match transaction.execute(
+            "insert into notification_outbox (
+                user_id,
+                token,
+                title,
+                body,
+                data,
+                push_priority,
+                android_channel
+            )
+            select user_id, token, $2, $3, $4, 'normal', 'zones'
+            from firebase_device_tokens
+            where user_id = $1
+            ",
+            &[&zone.user_id, &title, &body, &data],
+        ) {
+            Ok(count) => Ok(Some(count)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Claims due notifications, leasing them for as long as sending the batch may take.
+    pub fn claim_due(config: &NotificationConfig, transaction: &mut Transaction) -> Vec<Self> {
+        match transaction.query(
+            "with abandoned as (
+                update notification_outbox set
+                    status = 'dead',
+                    last_error = 'Lease expired on the last attempt',
+                    updated_at = now()
+                where status = 'sending'
+                    and next_attempt_at <= now()
+                    and attempts >= $2
+            )
+            update notification_outbox set
+                status = 'sending',
+                attempts = attempts + 1,
+                next_attempt_at = now() + make_interval(secs => $3),
+                updated_at = now()
+            where id in (
+                select id from notification_outbox
+                where status in ('pending', 'sending')
+                    and next_attempt_at <= now()
+                    and attempts < $2
+                order by next_attempt_at
+                limit $1
+                for update skip locked
+            )
+            returning *
+            ",
+            &[
+                &config.batch_size,
+                &config.max_attempts,
+                &config.lease().as_secs_f64(),
+            ],
+        ) {
+            Ok(rows) => rows
+                .iter()
+                .map(|row| outbox_notification!(row))
+                .collect::<Vec<OutboxNotification>>(),
+            Err(err) => {
+                error!("{}", err);
+                Vec::new()
+            }
+        }
+    }
+
+    // Once the lease runs out another worker may have claimed the notification again, the
+    // result of the stale attempt is dropped rather than overwriting the newer one
+    fn recorded(&self, updated: Result<u64, postgres::Error>) -> Result<(), ApiError> {
+        match updated {
+            Ok(0) => {
+                warn!(
+                    "Notification {} was claimed again before attempt {} was recorded",
+                    self.id, self.attempts
+                );
+                Ok(())
+            }
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn mark_sent(&self, transaction: &mut Transaction) -> Result<(), ApiError> {
+        self.recorded(transaction.execute(
+            "update notification_outbox set
+                status = 'sent',
+                last_error = null,
+                updated_at = now()
+            where id = $1 and status = 'sending' and attempts = $2
+            ",
+            &[&self.id, &self.attempts],
+        ))
+    }
+
+    /// Retries with exponential backoff, or dead-letters once out of attempts.
+    pub fn mark_failed(
+        &self,
+        reason: &str,
+        config: &NotificationConfig,
+        transaction: &mut Transaction,
+    ) -> Result<(), ApiError> {
+        self.recorded(transaction.execute(
+            "update notification_outbox set
+                status = case when attempts >= $3 then 'dead' else 'pending' end,
+                next_attempt_at = now()
+                    + make_interval(secs => least($4::float8 * power(2, attempts - 1), 3600)),
+                last_error = $2,
+                updated_at = now()
+            where id = $1 and status = 'sending' and attempts = $5
+            ",
+            &[
+                &self.id,
+                &reason,
+                &config.max_attempts,
+                &(config.retry_base_seconds as f64),
+                &self.attempts,
+            ],
+        ))
+    }
+
+    /// Dead-letters the notification without retrying, e.g. when its token is unregistered.
+    pub fn mark_dead(&self, reason: &str, transaction: &mut Transaction) -> Result<(), ApiError> {
+        self.recorded(transaction.execute(
+            "update notification_outbox set
+                status = 'dead',
+                last_error = $2,
+                updated_at = now()
+            where id = $1 and status = 'sending' and attempts = $3
+            ",
+            &[&self.id, &reason, &self.attempts],
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::database::test_client;
+
+    #[test]
+    #[ignore]
+    fn claim_due_dead_letters_leases_without_attempts_left() {
+        let mut client = test_client();
+        let mut transaction = client.transaction().unwrap();
+        transaction
+            .batch_execute(
+                "insert into users (id, name, email, password, phone)
+                values (-501, 'Recipient', 'recipient@outbox.test', 'x', '1');
+                insert into notification_outbox
+                    (id, user_id, token, title, body, status, attempts, next_attempt_at)
+                values
+                    (-501, -501, 'token', 'Title', 'Body', 'pending', 0, now()),
+                    (-502, -501, 'token', 'Title', 'Body', 'sending', 2, now() - interval '1 minute'),
+                    (-503, -501, 'token', 'Title', 'Body', 'sending', 5, now() - interval '1 minute');
+                ",
+            )
+            .unwrap();
+
+        let config = Config::for_tests();
+        let mut claimed = OutboxNotification::claim_due(&config.notifications, &mut transaction)
+            .iter()
+            .map(|notification| (notification.id, notification.attempts))
+            .collect::<Vec<(i64, i32)>>();
+        claimed.sort();
+        assert_eq!(claimed, vec![(-502, 3), (-501, 1)]);
+
+        let status: String = transaction
+            .query_one(
+                "select status from notification_outbox where id = -503",
+                &[],
+            )
+            .unwrap()
+            .get("status");
+        assert_eq!(status, "dead");
+    }
+
+    #[test]
+    #[ignore]
+    fn results_of_expired_leases_are_dropped() {
+        let mut client = test_client();
+        let mut transaction = client.transaction().unwrap();
+        transaction
+            .batch_execute(
+                "insert into users (id, name, email, password, phone)
+                values (-511, 'Recipient', 'recipient@leases.test', 'x', '1');
+                insert into notification_outbox
+                    (id, user_id, token, title, body, status, attempts, next_attempt_at)
+                values (-511, -511, 'token', 'Title', 'Body', 'pending', 0, now());
+                ",
+            )
+            .unwrap();
+
+        let config = Config::for_tests();
+        let stale = OutboxNotification::claim_due(&config.notifications, &mut transaction)
+            .pop()
+            .unwrap();
+
+        // The lease covers the whole batch timing out, the token exchange included
+        let leased: bool = transaction
+            .query_one(
+                "select next_attempt_at >= now() + make_interval(secs => $1) as leased
+                from notification_outbox where id = -511
+                ",
+                &[&(config.notifications.lease().as_secs_f64() - 1.0)],
+            )
+            .unwrap()
+            .get("leased");
+        assert!(leased);
+
+        // Another worker claims it again once the lease is up
+        transaction
+            .batch_execute("update notification_outbox set next_attempt_at = now() where id = -511")
+            .unwrap();
+        let current = OutboxNotification::claim_due(&config.notifications, &mut transaction)
+            .pop()
+            .unwrap();
+
+        stale.mark_sent(&mut transaction).unwrap();
+        stale
+            .mark_failed("Timed out", &config.notifications, &mut transaction)
+            .unwrap();
+        let status = |transaction: &mut Transaction| -> (String, i32) {
+            let row = transaction
+                .query_one(
+                    "select status, attempts from notification_outbox where id = -511",
+                    &[],
+                )
+                .unwrap();
+            (row.get("status"), row.get("attempts"))
+        };
+        assert_eq!(status(&mut transaction), (String::from("sending"), 2));
+
+        current.mark_sent(&mut transaction).unwrap();
+        assert_eq!(status(&mut transaction), (String::from("sent"), 2));
+    }
+}
@@ -0,0 +1,208 @@
+use crate::models::error::ApiError;
+use crate::models::geo::DistanceUnit;
+use crate::models::user::User;
+use chrono::{NaiveDateTime, NaiveTime};
+use postgres::Transaction;
+use serde::{Deserialize, Serialize};
+
+// Same cap as nearby searches, a personal radius can only narrow the alert type's radius anyway
+const MAX_RADIUS_MILES: f64 = 100.0;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationPreferences {
+    // In `distance_unit`, no radius means the alert type decides
+    pub radius: Option<f64>,
+
+    // Least severe alert level to be notified about, 1 is the most severe
+    #[serde(rename = "maxAlertLevel")]
+    pub max_alert_level: i16,
+
+    #[serde(rename = "mutedAlertTypes")]
+    pub muted_alert_types: Vec<String>,
+
+    #[serde(rename = "quietHoursStart")]
+    pub quiet_hours_start: Option<NaiveTime>,
+
+    #[serde(rename = "quietHoursEnd")]
+    pub quiet_hours_end: Option<NaiveTime>,
+
+    pub timezone: String,
+
+    // Level 1 alerts still come through during quiet hours
+    #[serde(rename = "emergencyOverride")]
+    pub emergency_override: bool,
+
+    #[serde(rename = "distanceUnit")]
+    pub distance_unit: String,
+
+    #[serde(rename = "updatedAt")]
+    #[serde(skip_deserializing)]
+    pub updated_at: Option<NaiveDateTime>,
+}
+
+impl Default for NotificationPreferences {
+    fn default() -> Self {
+        NotificationPreferences {
+            radius: None,
+            max_alert_level: 3,
+            muted_alert_types: Vec::new(),
+            quiet_hours_start: None,
+            quiet_hours_end: None,
+            timezone: String::from("UTC"),
+            emergency_override: true,
+            distance_unit: String::from(DistanceUnit::Miles.abbreviation()),
+            updated_at: None,
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! notification_preferences {
+    ($row:expr) => {{
+        let distance_unit: String = $row.get("distance_unit");
+        let unit = DistanceUnit::parse(&distance_unit).unwrap_or(DistanceUnit::Miles);
+        NotificationPreferences {
+            radius: $row
+                .get::<_, Option<f32>>("radius_miles")
+                .map(|radius| unit.convert_miles(f64::from(radius))),
+            max_alert_level: $row.get("max_alert_level"),
+            muted_alert_types: $row.get("muted_alert_types"),
+            quiet_hours_start: $row.get("quiet_hours_start"),
+            quiet_hours_end: $row.get("quiet_hours_end"),
+            timezone: $row.get("timezone"),
+            emergency_override: $row.get("emergency_override"),
+            distance_unit,
+            updated_at: $row.get("updated_at"),
+        }
+    }};
+}
+
+impl NotificationPreferences {
+    pub fn get_for_user(user: &User, transaction: &mut Transaction) -> Self {
+        match transaction.query_opt(
+            "select * from notification_preferences where user_id = $1
+            ",
+            &[&user.id],
+        ) {
+            Ok(Some(row)) => notification_preferences!(row),
+            Ok(None) => Self::default(),
+            Err(err) => {
+                error!("{}", err);
+                Self::default()
+            }
+        }
+    }
+
+    fn validate(&self, unit: DistanceUnit) -> Result<(), ApiError> {
+        if let Some(radius) = self.radius {
+            let radius_miles = unit.to_miles(radius);
+            if !(radius_miles > 0.0 && radius_miles <= MAX_RADIUS_MILES) {
+                return Err(ApiError::validation(
+                    "radius",
+                    &format!(
+                        "Must be positive and at most {:.0} {}",
+                        unit.convert_miles(MAX_RADIUS_MILES),
+                        unit.abbreviation()
+                    ),
+                ));
+            }
+        }
+
+        if !(1..=3).contains(&self.max_alert_level) {
+            return Err(ApiError::validation(
+                "maxAlertLevel",
+                "Must be between 1 and 3",
+            ));
+        }
+
+        if self.quiet_hours_start.is_some() != self.quiet_hours_end.is_some() {
+            return Err(ApiError::validation(
+                "quietHoursEnd",
+                "Quiet hours need both a start and an end",
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self, user: &User, transaction: &mut Transaction) -> Result<Self, ApiError> {
+        let unit = match DistanceUnit::parse(&self.distance_unit) {
+            Some(unit) => unit,
+            None => return Err(ApiError::validation("distanceUnit", "Must be mi or km")),
+        };
+
+        self.validate(unit)?;
+
+        match transaction.query_one(
+            "select
+                exists (select 1 from pg_timezone_names where name = $1) as valid_timezone,
+                array(
+                    select muted from unnest($2::text[]) as muted
+                    where muted not in (select name from alert_types)
+                ) as unknown_types
+            ",
+            &[&self.timezone, &self.muted_alert_types],
+        ) {
+            Ok(row) => {
+                if !row.get::<_, bool>("valid_timezone") {
+                    return Err(ApiError::validation(
+                        "timezone",
+                        &format!("Unknown timezone {}", self.timezone),
+                    ));
+                }
+
+                let unknown_types: Vec<String> = row.get("unknown_types");
+                if !unknown_types.is_empty() {
+                    return Err(ApiError::validation(
+                        "mutedAlertTypes",
+                        &format!("Unknown alert type(s) {}", unknown_types.join(", ")),
+                    ));
+                }
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        let radius_miles = self.radius.map(|radius| unit.to_miles(radius) as f32);
+
+        match transaction.query_one(
+            "insert into notification_preferences (
+                user_id,
+                radius_miles,
+                max_alert_level,
+                muted_alert_types,
+                quiet_hours_start,
+                quiet_hours_end,
+                timezone,
+                emergency_override,
+                distance_unit
+            ) values ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            on conflict (user_id) do update set
+                radius_miles = excluded.radius_miles,
+                max_alert_level = excluded.max_alert_level,
+                muted_alert_types = excluded.muted_alert_types,
+                quiet_hours_start = excluded.quiet_hours_start,
+                quiet_hours_end = excluded.quiet_hours_end,
+                timezone = excluded.timezone,
+                emergency_override = excluded.emergency_override,
+                distance_unit = excluded.distance_unit,
+                updated_at = now()
+            returning *
+            ",
+            &[
+                &user.id,
+                &radius_miles,
+                &self.max_alert_level,
+                &self.muted_alert_types,
+                &self.quiet_hours_start,
+                &self.quiet_hours_end,
+                &self.timezone,
+                &self.emergency_override,
+                &unit.abbreviation(),
+            ],
+        ) {
+            Ok(row) => Ok(notification_preferences!(row)),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
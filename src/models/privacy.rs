@@ -0,0 +1,88 @@
+use crate::config::LocationConfig;
+use crate::models::error::ApiError;
+use crate::models::user::User;
+use chrono::NaiveDateTime;
+use postgres::Transaction;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrivacySettings {
+    // exact or approximate, approximate locations are snapped to the privacy grid everywhere
+    // but in alert targeting
+    #[serde(rename = "locationPrecision")]
+    pub location_precision: String,
+
+    #[serde(rename = "updatedAt")]
+    #[serde(skip_deserializing)]
+    pub updated_at: Option<NaiveDateTime>,
+}
+
+impl Default for PrivacySettings {
+    fn default() -> Self {
+        PrivacySettings {
+            location_precision: String::from("exact"),
+            updated_at: None,
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! privacy_settings {
+    ($row:expr) => {
+        PrivacySettings {
+            location_precision: $row.get("location_precision"),
+            updated_at: $row.get("updated_at"),
+        }
+    };
+}
+
+impl PrivacySettings {
+    pub fn get_for_user(user_id: i64, transaction: &mut Transaction) -> Self {
+        match transaction.query_opt(
+            "select * from privacy_settings where user_id = $1
+            ",
+            &[&user_id],
+        ) {
+            Ok(Some(row)) => privacy_settings!(row),
+            Ok(None) => Self::default(),
+            Err(err) => {
+                error!("{}", err);
+                Self::default()
+            }
+        }
+    }
+
+    /// Grid cell size in meters for the user's location, `None` when it is kept exact.
+    pub fn location_grid(&self, config: &LocationConfig) -> Option<f64> {
+        match self.location_precision.as_str() {
+            "approximate" => Some(config.privacy_grid_meters),
+            _ => None,
+        }
+    }
+
+    pub fn save(&self, user: &User, transaction: &mut Transaction) -> Result<Self, ApiError> {
+        if !["exact", "approximate"].contains(&self.location_precision.as_str()) {
+            return Err(ApiError::validation(
+                "locationPrecision",
+                "Must be exact or approximate",
+            ));
+        }
+
+        match transaction.query_one(
+            "insert into privacy_settings (
+                user_id,
+                location_precision
+            ) values ($1, $2)
+            on conflict (user_id) do update set
+                location_precision = excluded.location_precision,
+                updated_at = now()
+            returning *
+            ",
+            &[&user.id, &self.location_precision],
+        ) {
+            Ok(row) => Ok(privacy_settings!(row)),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use crate::config::Config;
+use crate::models::auth::{generate_refresh_token, hash_token, validate_token, TokenType};
+use crate::models::database::PGPool;
+use crate::models::error::ApiError;
+use chrono::{Duration, NaiveDateTime, Utc};
+use postgres::Transaction;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub id: i64,
+
+    #[serde(skip)]
+    pub user_id: i64,
+
+    #[serde(rename = "deviceName")]
+    pub device_name: Option<String>,
+
+    pub platform: Option<String>,
+
+    #[serde(rename = "ipAddress")]
+    pub ip_address: Option<String>,
+
+    // Whether this is the session making the request
+    pub current: bool,
+
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<NaiveDateTime>,
+
+    #[serde(rename = "lastSeenAt")]
+    pub last_seen_at: Option<NaiveDateTime>,
+
+    #[serde(skip)]
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[macro_export]
+macro_rules! session {
+    ($row:expr) => {
+        Session {
+            id: $row.get("id"),
+            user_id: $row.get("user_id"),
+            device_name: $row.get("device_name"),
+            platform: $row.get("platform"),
+            ip_address: $row.get("ip_address"),
+            current: false,
+            created_at: $row.get("created_at"),
+            last_seen_at: $row.get("last_seen_at"),
+            revoked_at: $row.get("revoked_at"),
+        }
+    };
+}
+
+/// Details about the device a session was started from, taken from the request headers.
+#[derive(Debug)]
+pub struct ClientInfo {
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+#[macro_export]
+macro_rules! fetch_session {
+    ($token:expr, $config:expr, $transaction:expr) => {
+        match Session::from_token($token, $config, $transaction) {
+            Some(session) => session,
+            None => {
+                return $crate::models::error::ApiError::Unauthorized(String::from(
+                    "Session not found or token is invalid",
+                ))
+                .into()
+            }
+        }
+    };
+}
+
+pub enum RefreshOutcome {
+    Rotated(Session, String),
+    Reused,
+}
+
+impl Session {
+    pub fn init(
+        user_id: i64,
+        client: &ClientInfo,
+        transaction: &mut Transaction,
+    ) -> Result<Self, ApiError> {
+        match transaction.query_one(
+            "insert into sessions (
+                user_id,
+                device_name,
+                platform,
+                ip_address
+            ) values ($1, $2, $3, $4)
+            returning *
+            ",
+            &[
+                &user_id,
+                &client.device_name,
+                &client.platform,
+                &client.ip_address,
+            ],
+        ) {
+            Ok(row) => Ok(session!(row)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Looks up the session an access token was issued for.
+    pub fn from_token(
+        token: String,
+        config: &Config,
+        transaction: &mut Transaction,
+    ) -> Option<Self> {
+        let session_id = match validate_token(token, TokenType::Auth, config) {
+            Ok(claims) => claims.sid?,
+            Err(_) => return None,
+        };
+
+        match transaction.query_opt(
+            "select * from sessions
+            where id = $1
+                and revoked_at is null
+            ",
+            &[&session_id],
+        ) {
+            Ok(row) => row.map(|row| {
+                let mut session = session!(row);
+                session.current = true;
+                session
+            }),
+            Err(err) => {
+                error!("{}", err);
+                None
+            }
+        }
+    }
+
+    /// Refreshes the last seen time of the access token's session, at most once a minute. Runs
+    /// on its own connection so requests that never commit still count, and is skipped when no
+    /// connection is free.
+    pub fn record_activity(token: &str, config: &Config, pool: &PGPool) {
+        let session_id = match validate_token(String::from(token), TokenType::Auth, config) {
+            Ok(claims) => match claims.sid {
+                Some(session_id) => session_id,
+                None => return,
+            },
+            Err(_) => return,
+        };
+
+        let mut client = match pool.try_get() {
+            Some(client) => client,
+            None => return,
+        };
+
+        if let Err(err) = client.execute(
+            "update sessions set
+                last_seen_at = now()
+            where id = $1
+                and revoked_at is null
+                and (last_seen_at is null or last_seen_at < now() - interval '1 minute')
+            ",
+            &[&session_id],
+        ) {
+            error!("{}", err);
+        }
+    }
+
+    pub fn get_active_for_user(user_id: i64, transaction: &mut Transaction) -> Vec<Self> {
+        match transaction.query(
+            "select * from sessions
+            where user_id = $1
+                and revoked_at is null
+            order by last_seen_at desc
+            ",
+            &[&user_id],
+        ) {
+            Ok(rows) => rows
+                .iter()
+                .map(|row| session!(row))
+                .collect::<Vec<Session>>(),
+            Err(err) => {
+                error!("{}", err);
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn get_active_by_id(id: i64, user_id: i64, transaction: &mut Transaction) -> Option<Self> {
+        match transaction.query_opt(
+            "select * from sessions
+            where id = $1
+                and user_id = $2
+                and revoked_at is null
+            ",
+            &[&id, &user_id],
+        ) {
+            Ok(row) => row.map(|row| session!(row)),
+            Err(err) => {
+                error!("{}", err);
+                None
+            }
+        }
+    }
+
+    pub fn issue_refresh_token(
+        &self,
+        config: &Config,
+        transaction: &mut Transaction,
+    ) -> Result<String, ApiError> {
+        let refresh_token = generate_refresh_token();
+        let expires_at = (Utc::now() + Duration::days(config.auth.refresh_token_days)).naive_utc();
+
+        match transaction.execute(
+            "insert into refresh_tokens (
+                token_hash,
+                session_id,
+                expires_at
+            ) values ($1, $2, $3)
+            ",
+            &[&hash_token(&refresh_token), &self.id, &expires_at],
+        ) {
+            Ok(_) => Ok(refresh_token),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Exchanges a refresh token for a new one. Presenting an already used refresh token means
+    /// it was copied, so the whole session is revoked and `Reused` is returned.
+    pub fn refresh(
+        refresh_token: &str,
+        client: &ClientInfo,
+        config: &Config,
+        transaction: &mut Transaction,
+    ) -> Result<RefreshOutcome, ApiError> {
+        let row = match transaction.query_opt(
+            "select
+                s.*,
+                rt.used_at,
+                rt.expires_at < now() as expired
+            from refresh_tokens rt
+            inner join sessions s
+                on rt.session_id = s.id
+            where rt.token_hash = $1
+            for update
+            ",
+            &[&hash_token(refresh_token)],
+        ) {
+            Ok(Some(row)) => row,
+            Ok(None) => {
+                return Err(ApiError::Unauthorized(String::from(
+                    "Refresh token is invalid",
+                )))
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let session = session!(row);
+
+        if session.revoked_at.is_some() {
+            return Err(ApiError::Unauthorized(String::from(
+                "Session has been revoked, please log in again",
+            )));
+        }
+
+        if row.get::<_, Option<NaiveDateTime>>("used_at").is_some() {
+            warn!(
+                "Refresh token reused for session {}, revoking session",
+                session.id
+            );
+            session.revoke(transaction)?;
+            return Ok(RefreshOutcome::Reused);
+        }
+
+        if row.get::<_, bool>("expired") {
+            return Err(ApiError::Unauthorized(String::from(
+                "Refresh token has expired, please log in again",
+            )));
+        }
+
+        if let Err(err) = transaction.execute(
+            "update refresh_tokens set used_at = now() where token_hash = $1
+            ",
+            &[&hash_token(refresh_token)],
+        ) {
+            return Err(err.into());
+        }
+
+        if let Err(err) = transaction.execute(
+            "update sessions set
+                last_seen_at = now(),
+                ip_address = coalesce($2, ip_address)
+            where id = $1
+            ",
+            &[&session.id, &client.ip_address],
+        ) {
+            return Err(err.into());
+        }
+
+        let new_token = session.issue_refresh_token(config, transaction)?;
+        Ok(RefreshOutcome::Rotated(session, new_token))
+    }
+
+    pub fn revoke(&self, transaction: &mut Transaction) -> Result<(), ApiError> {
+        match transaction.execute(
+            "delete from firebase_device_tokens where session_id = $1
+            ",
+            &[&self.id],
+        ) {
+            Ok(count) => info!("Removed {} device token(s) of session {}", count, self.id),
+            Err(err) => return Err(err.into()),
+        }
+
+        match transaction.execute(
+            "update sessions set revoked_at = now() where id = $1 and revoked_at is null
+            ",
+            &[&self.id],
+        ) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn revoke_all(user_id: i64, transaction: &mut Transaction) -> Result<u64, ApiError> {
+        if let Err(err) = transaction.execute(
+            "delete from firebase_device_tokens
+            where session_id in (select id from sessions where user_id = $1)
+            ",
+            &[&user_id],
+        ) {
+            return Err(err.into());
+        }
+
+        match transaction.execute(
+            "update sessions set revoked_at = now() where user_id = $1 and revoked_at is null
+            ",
+            &[&user_id],
+        ) {
+            Ok(count) => Ok(count),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
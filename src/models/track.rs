@@ -0,0 +1,135 @@
+use crate::models::alerts::Alert;
+use crate::models::error::ApiError;
+use crate::models::geo::simplify_path;
+use crate::models::location::Location;
+use chrono::NaiveDateTime;
+use postgres::Transaction;
+use serde::{Deserialize, Serialize};
+
+/// One position on the trail of an alert that follows its creator.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrackPoint {
+    pub latitude: f32,
+
+    pub longitude: f32,
+
+    // In meters
+    pub accuracy: Option<f32>,
+
+    #[serde(rename = "recordedAt")]
+    pub recorded_at: NaiveDateTime,
+}
+
+#[macro_export]
+macro_rules! track_point {
+    ($row:expr) => {
+        TrackPoint {
+            latitude: $row.get("latitude"),
+            longitude: $row.get("longitude"),
+            accuracy: $row.get("accuracy"),
+            recorded_at: $row.get("recorded_at"),
+        }
+    };
+}
+
+impl TrackPoint {
+    /// Adds the alert's current position to its trail if it is tracked, so the trail starts
+    /// where the alert was created or where its creator moved it.
+    pub fn record_current_position(
+        alert: &Alert,
+        transaction: &mut Transaction,
+    ) -> Result<(), ApiError> {
+        match transaction.execute(
+            "insert into alert_track_points (alert_id, latitude, longitude, recorded_at)
+            select id, latitude, longitude, coalesce(updated_at, now())
+            from alerts
+            where id = $1
+                and track_location
+            on conflict (alert_id, recorded_at) do nothing
+            ",
+            &[&alert.id],
+        ) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Adds uploaded points to the trails of the user's ongoing tracked alerts. Points from
+    /// before an alert was created are left out.
+    pub fn record_for_user(
+        user_id: i64,
+        locations: &[Location],
+        transaction: &mut Transaction,
+    ) -> Result<u64, ApiError> {
+        match transaction.execute(
+            "insert into alert_track_points (alert_id, latitude, longitude, accuracy, recorded_at)
+            select a.id, p.latitude, p.longitude, p.accuracy, p.recorded_at
+            from alerts a
+            inner join users u
+                on a.created_by = u.email
+            cross join unnest($2::real[], $3::real[], $4::real[], $5::timestamp[])
+                as p(latitude, longitude, accuracy, recorded_at)
+            where u.id = $1
+                and a.track_location
+                and not a.is_resolved
+                and (a.expires_at is null or a.expires_at > now())
+                and p.recorded_at >= a.created_at
+            on conflict (alert_id, recorded_at) do nothing
+            ",
+            &[
+                &user_id,
+                &locations.iter().map(|l| l.latitude).collect::<Vec<f32>>(),
+                &locations.iter().map(|l| l.longitude).collect::<Vec<f32>>(),
+                &locations
+                    .iter()
+                    .map(|l| l.accuracy)
+                    .collect::<Vec<Option<f32>>>(),
+                &locations
+                    .iter()
+                    .map(|l| l.recorded_at)
+                    .collect::<Vec<Option<NaiveDateTime>>>(),
+            ],
+        ) {
+            Ok(count) => Ok(count),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The alert's trail, oldest point first.
+    pub fn get_for_alert(alert_id: i64, transaction: &mut Transaction) -> Vec<Self> {
+        match transaction.query(
+            "select * from alert_track_points
+            where alert_id = $1
+            order by recorded_at
+            ",
+            &[&alert_id],
+        ) {
+            Ok(rows) => rows
+                .iter()
+                .map(|row| track_point!(row))
+                .collect::<Vec<TrackPoint>>(),
+            Err(err) => {
+                error!("{}", err);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Drops the points that stray less than `tolerance_meters` from the simplified trail.
+    pub fn simplify(points: Vec<Self>, tolerance_meters: f64) -> Vec<Self> {
+        let path = points
+            .iter()
+            .map(|point| (f64::from(point.latitude), f64::from(point.longitude)))
+            .collect::<Vec<(f64, f64)>>();
+        let mut kept = simplify_path(&path, tolerance_meters)
+            .into_iter()
+            .peekable();
+
+        points
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| kept.next_if_eq(index).is_some())
+            .map(|(_, point)| point)
+            .collect()
+    }
+}
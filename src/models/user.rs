@@ -3,9 +3,12 @@ use chrono::NaiveDateTime;
 use postgres::Transaction;
 use serde::{Deserialize, Serialize};
 
+use crate::config::Config;
 use crate::location;
 use crate::models::auth::{validate_token, TokenType};
+use crate::models::error::ApiError;
 use crate::models::location::Location;
+use crate::models::session::Session;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
@@ -51,27 +54,30 @@ macro_rules! user {
 
 #[macro_export]
 macro_rules! fetch_user {
-    ($token:expr, $token_type:expr, $transaction:expr) => {
-        match User::from_token($token, $token_type, $transaction) {
+    ($token:expr, $token_type:expr, $config:expr, $transaction:expr) => {
+        match User::from_token($token, $token_type, $config, $transaction) {
             Some(user) => user,
-            None => return StandardResponse {
-                status: Status::BadRequest,
-                response: json!({
-                    "message": "User not found or token is invalid"
-                })
+            None => {
+                return $crate::models::error::ApiError::Unauthorized(String::from(
+                    "User not found or token is invalid",
+                ))
+                .into()
             }
         }
     };
 }
 
 impl User {
-    pub fn init(&self, transaction: &mut Transaction) -> Result<Self, String> {
+    pub fn init(&self, transaction: &mut Transaction) -> Result<Self, ApiError> {
         let password_hash = match hash(self.password.as_str(), DEFAULT_COST) {
             Ok(hash) => hash,
-            Err(err) => return Err(err.to_string()),
+            Err(err) => {
+                error!("{}", err);
+                return Err(ApiError::Internal(String::from("Could not hash password")));
+            }
         };
 
-        match transaction.query_one(
+        match transaction.query_opt(
             "insert into users (
                 name,
                 email,
@@ -83,21 +89,29 @@ impl User {
             ",
             &[&self.name, &self.email, &password_hash, &self.phone],
         ) {
-            Ok(row) => Ok(user!(row)),
-            Err(err) => {
-                error!("{}", err);
-                Err(String::from("Couldn't create new user"))
-            }
+            Ok(Some(row)) => Ok(user!(row)),
+            Ok(None) => Err(ApiError::Conflict(format!(
+                "User already registered with email {}",
+                self.email
+            ))),
+            Err(err) => Err(err.into()),
         }
     }
 
     pub fn from_token(
         token: String,
         token_type: TokenType,
+        config: &Config,
         transaction: &mut Transaction,
     ) -> Option<Self> {
-        let user_email = match validate_token(token, token_type) {
-            Ok(data) => data,
+        if token_type == TokenType::Auth {
+            // Auth tokens are only honoured while their session is still active
+            let session = Session::from_token(token, config, transaction)?;
+            return Self::from_id(session.user_id, transaction);
+        }
+
+        let user_email = match validate_token(token, token_type, config) {
+            Ok(claims) => claims.sub,
             Err(_) => return None,
         };
 
@@ -132,7 +146,7 @@ impl User {
         }
     }
 
-    pub fn verify_email(&self, transaction: &mut Transaction) -> Result<Self, String> {
+    pub fn verify_email(&self, transaction: &mut Transaction) -> Result<Self, ApiError> {
         match transaction.query_one(
             "update users set
                 verified = true,
@@ -143,10 +157,7 @@ impl User {
             &[&self.id],
         ) {
             Ok(row) => Ok(user!(row)),
-            Err(err) => {
-                error!("{}", err);
-                Err(String::from("Could not update user verification status"))
-            }
+            Err(err) => Err(err.into()),
         }
     }
 
@@ -168,10 +179,13 @@ impl User {
         &self,
         password: String,
         transaction: &mut Transaction,
-    ) -> Result<Self, String> {
+    ) -> Result<Self, ApiError> {
         let password_hash = match hash(password.as_str(), DEFAULT_COST) {
             Ok(hash) => hash,
-            Err(err) => return Err(err.to_string()),
+            Err(err) => {
+                error!("{}", err);
+                return Err(ApiError::Internal(String::from("Could not hash password")));
+            }
         };
 
         match transaction.query_one(
@@ -180,37 +194,12 @@ impl User {
             ",
             &[&password_hash, &self.id],
         ) {
-            Ok(row) => Ok(user!(row)),
-            Err(err) => {
-                error!("{}", err);
-                Err(String::from("Could not reset user's password"))
-            }
-        }
-    }
-
-    pub fn update_device_token(
-        &self,
-        device_token: String,
-        transaction: &mut Transaction,
-    ) -> Result<(), String> {
-        match transaction.query_one(
-            "insert into firebase_device_tokens (
-                user_id, 
-                token,
-                updated_at
-            ) values ($1, $2, now())
-            on conflict (user_id) do update set 
-                token = excluded.token,
-                updated_at = now()
-            returning *
-        ",
-            &[&self.id, &device_token],
-        ) {
-            Ok(_) => return Ok(()),
-            Err(err) => {
-                error!("{}", err);
-                return Err(String::from("Error while updating token"));
+            Ok(row) => {
+                // Whoever knew the old password may still hold a session
+                Session::revoke_all(self.id, transaction)?;
+                Ok(user!(row))
             }
+            Err(err) => Err(err.into()),
         }
     }
 }
@@ -0,0 +1,376 @@
+use crate::models::error::ApiError;
+use crate::models::geo::{is_valid_coordinate, BoundingBox};
+use crate::models::user::User;
+use chrono::NaiveDateTime;
+use postgres::Transaction;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const MAX_ZONES_PER_USER: i64 = 20;
+const MAX_ZONE_NAME_LENGTH: usize = 50;
+const MAX_RADIUS_MILES: f64 = 25.0;
+const MAX_POLYGON_CORNERS: usize = 100;
+
+/// A place a user wants alerts around, like home, school or work.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WatchZone {
+    #[serde(skip_deserializing)]
+    pub id: i64,
+
+    #[serde(skip)]
+    pub user_id: i64,
+
+    pub name: String,
+
+    // Either circle or polygon
+    pub kind: String,
+
+    // Center of circular zones
+    pub latitude: Option<f64>,
+
+    pub longitude: Option<f64>,
+
+    // Radius of circular zones in miles
+    pub radius: Option<f64>,
+
+    // [latitude, longitude] corners of polygon zones
+    pub polygon: Option<Vec<[f64; 2]>>,
+
+    // Whether the user is notified when they enter or leave the zone
+    #[serde(rename = "notifyTransitions")]
+    #[serde(default)]
+    pub notify_transitions: bool,
+
+    #[serde(rename = "createdAt")]
+    #[serde(skip_deserializing)]
+    pub created_at: Option<NaiveDateTime>,
+
+    #[serde(rename = "updatedAt")]
+    #[serde(skip_deserializing)]
+    pub updated_at: Option<NaiveDateTime>,
+}
+
+#[macro_export]
+macro_rules! watch_zone {
+    ($row:expr) => {
+        WatchZone {
+            id: $row.get("id"),
+            user_id: $row.get("user_id"),
+            name: $row.get("name"),
+            kind: $row.get("kind"),
+            latitude: $row.get("latitude"),
+            longitude: $row.get("longitude"),
+            radius: $row.get("radius_miles"),
+            polygon: $row
+                .get::<_, Option<Value>>("polygon")
+                .and_then(|polygon| serde_json::from_value(polygon).ok()),
+            notify_transitions: $row.get("notify_transitions"),
+            created_at: $row.get("created_at"),
+            updated_at: $row.get("updated_at"),
+        }
+    };
+}
+
+/// The shape of a zone as stored: the polygon in the database's (longitude, latitude) point
+/// syntax, if it is one, and the bounding box used to prefilter zones.
+struct ZoneShape {
+    boundary: Option<String>,
+    bounds: BoundingBox,
+}
+
+impl WatchZone {
+    fn validate(&self) -> Result<ZoneShape, ApiError> {
+        let name = self.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_ZONE_NAME_LENGTH {
+            return Err(ApiError::validation(
+                "name",
+                &format!("Must be between 1 and {} characters", MAX_ZONE_NAME_LENGTH),
+            ));
+        }
+
+        match self.kind.as_str() {
+            "circle" => {
+                let (latitude, longitude) = match (self.latitude, self.longitude) {
+                    (Some(latitude), Some(longitude))
+                        if is_valid_coordinate(latitude, longitude) =>
+                    {
+                        (latitude, longitude)
+                    }
+                    _ => {
+                        return Err(ApiError::validation(
+                            "latitude",
+                            "Circular zones need a valid center",
+                        ))
+                    }
+                };
+
+                match self.radius {
+                    Some(radius) if radius > 0.0 && radius <= MAX_RADIUS_MILES => Ok(ZoneShape {
+                        boundary: None,
+                        bounds: BoundingBox::around(latitude, longitude, radius),
+                    }),
+                    _ => Err(ApiError::validation(
+                        "radius",
+                        &format!("Must be positive and at most {:.0} miles", MAX_RADIUS_MILES),
+                    )),
+                }
+            }
+            "polygon" => {
+                let corners = match &self.polygon {
+                    Some(corners) if (3..=MAX_POLYGON_CORNERS).contains(&corners.len()) => corners,
+                    _ => {
+                        return Err(ApiError::validation(
+                            "polygon",
+                            &format!("Must have between 3 and {} corners", MAX_POLYGON_CORNERS),
+                        ))
+                    }
+                };
+
+                if !corners
+                    .iter()
+                    .all(|[latitude, longitude]| is_valid_coordinate(*latitude, *longitude))
+                {
+                    return Err(ApiError::validation(
+                        "polygon",
+                        "Corners must be valid [latitude, longitude] pairs",
+                    ));
+                }
+
+                let points = corners
+                    .iter()
+                    .map(|[latitude, longitude]| format!("({},{})", longitude, latitude))
+                    .collect::<Vec<String>>();
+
+                Ok(ZoneShape {
+                    boundary: Some(format!("({})", points.join(","))),
+                    bounds: BoundingBox {
+                        min_latitude: corners.iter().map(|c| c[0]).fold(f64::MAX, f64::min),
+                        max_latitude: corners.iter().map(|c| c[0]).fold(f64::MIN, f64::max),
+                        min_longitude: corners.iter().map(|c| c[1]).fold(f64::MAX, f64::min),
+                        max_longitude: corners.iter().map(|c| c[1]).fold(f64::MIN, f64::max),
+                    },
+                })
+            }
+            _ => Err(ApiError::validation("kind", "Must be circle or polygon")),
+        }
+    }
+
+    // Only the fields of the zone's kind are kept
+    fn polygon_json(&self) -> Option<Value> {
+        match self.kind.as_str() {
+            "polygon" => self
+                .polygon
+                .as_ref()
+                .and_then(|polygon| serde_json::to_value(polygon).ok()),
+            _ => None,
+        }
+    }
+
+    fn circle(&self) -> (Option<f64>, Option<f64>, Option<f64>) {
+        match self.kind.as_str() {
+            "circle" => (self.latitude, self.longitude, self.radius),
+            _ => (None, None, None),
+        }
+    }
+
+    // Zone names are unique per user, and users only get so many zones
+    fn check_available(
+        &self,
+        user_id: i64,
+        except_id: i64,
+        transaction: &mut Transaction,
+    ) -> Result<(), ApiError> {
+        match transaction.query_one(
+            "select
+                count(*) as zones,
+                coalesce(bool_or(name = $3), false) as name_taken
+            from watch_zones
+            where user_id = $1 and id <> $2
+            ",
+            &[&user_id, &except_id, &self.name.trim()],
+        ) {
+            Ok(row) if row.get::<_, bool>("name_taken") => Err(ApiError::validation(
+                "name",
+                &format!("You already have a zone named {}", self.name.trim()),
+            )),
+            Ok(row) if row.get::<_, i64>("zones") >= MAX_ZONES_PER_USER => {
+                Err(ApiError::validation(
+                    "zones",
+                    &format!("You can have at most {} zones", MAX_ZONES_PER_USER),
+                ))
+            }
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn get_for_user(user: &User, transaction: &mut Transaction) -> Vec<Self> {
+        match transaction.query(
+            "select * from watch_zones where user_id = $1
+            order by name
+            ",
+            &[&user.id],
+        ) {
+            Ok(rows) => rows
+                .iter()
+                .map(|row| watch_zone!(row))
+                .collect::<Vec<WatchZone>>(),
+            Err(err) => {
+                error!("{}", err);
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn get_by_id(id: i64, user: &User, transaction: &mut Transaction) -> Option<Self> {
+        match transaction.query_opt(
+            "select * from watch_zones where id = $1 and user_id = $2
+            ",
+            &[&id, &user.id],
+        ) {
+            Ok(row) => row.map(|row| watch_zone!(row)),
+            Err(err) => {
+                error!("{}", err);
+                None
+            }
+        }
+    }
+
+    pub fn init(&self, user: &User, transaction: &mut Transaction) -> Result<Self, ApiError> {
+        let shape = self.validate()?;
+        let (latitude, longitude, radius) = self.circle();
+
+        self.check_available(user.id, 0, transaction)?;
+
+        match transaction.query_one(
+            "insert into watch_zones (
+                user_id,
+                name,
+                kind,
+                latitude,
+                longitude,
+                radius_miles,
+                polygon,
+                boundary,
+                min_latitude,
+                max_latitude,
+                min_longitude,
+                max_longitude,
+                notify_transitions
+            ) values ($1, $2, $3, $4, $5, $6, $7, $8::text::polygon, $9, $10, $11, $12, $13)
+            returning *
+            ",
+            &[
+                &user.id,
+                &self.name.trim(),
+                &self.kind,
+                &latitude,
+                &longitude,
+                &radius,
+                &self.polygon_json(),
+                &shape.boundary,
+                &shape.bounds.min_latitude,
+                &shape.bounds.max_latitude,
+                &shape.bounds.min_longitude,
+                &shape.bounds.max_longitude,
+                &self.notify_transitions,
+            ],
+        ) {
+            Ok(row) => Ok(watch_zone!(row)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn update(&self, new: &Self, transaction: &mut Transaction) -> Result<Self, ApiError> {
+        let shape = new.validate()?;
+        let (latitude, longitude, radius) = new.circle();
+        new.check_available(self.user_id, self.id, transaction)?;
+
+        match transaction.query_one(
+            "update watch_zones set
+                name = $2,
+                kind = $3,
+                latitude = $4,
+                longitude = $5,
+                radius_miles = $6,
+                polygon = $7,
+                boundary = $8::text::polygon,
+                min_latitude = $9,
+                max_latitude = $10,
+                min_longitude = $11,
+                max_longitude = $12,
+                notify_transitions = $13,
+                updated_at = now()
+            where id = $1
+            returning *
+            ",
+            &[
+                &self.id,
+                &new.name.trim(),
+                &new.kind,
+                &latitude,
+                &longitude,
+                &radius,
+                &new.polygon_json(),
+                &shape.boundary,
+                &shape.bounds.min_latitude,
+                &shape.bounds.max_latitude,
+                &shape.bounds.min_longitude,
+                &shape.bounds.max_longitude,
+                &new.notify_transitions,
+            ],
+        ) {
+            Ok(row) => Ok(watch_zone!(row)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn delete(&self, transaction: &mut Transaction) -> Result<(), ApiError> {
+        match transaction.execute(
+            "delete from watch_zones where id = $1
+            ",
+            &[&self.id],
+        ) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The user's zones with transition notifications that moving between the two points
+    /// crossed, each with whether the user entered it.
+    pub fn crossed(
+        user_id: i64,
+        from: (f32, f32),
+        to: (f32, f32),
+        transaction: &mut Transaction,
+    ) -> Result<Vec<(Self, bool)>, ApiError> {
+        match transaction.query(
+            "select * from (
+                select
+                    z.*,
+                    zone_contains(z.latitude, z.longitude, z.radius_miles, z.boundary, $2, $3)
+                        as was_inside,
+                    zone_contains(z.latitude, z.longitude, z.radius_miles, z.boundary, $4, $5)
+                        as is_inside
+                from watch_zones z
+                where z.user_id = $1
+                    and z.notify_transitions
+            ) zones
+            where was_inside <> is_inside
+            ",
+            &[
+                &user_id,
+                &f64::from(from.0),
+                &f64::from(from.1),
+                &f64::from(to.0),
+                &f64::from(to.1),
+            ],
+        ) {
+            Ok(rows) => Ok(rows
+                .iter()
+                .map(|row| (watch_zone!(row), row.get("is_inside")))
+                .collect::<Vec<(WatchZone, bool)>>()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
@@ -1,15 +1,22 @@
+use crate::config::Config;
 use lettre::smtp::authentication::IntoCredentials;
 use lettre::{SmtpClient, Transport};
 use lettre_email::EmailBuilder;
-use std::env;
 
-pub fn send_email(to: String, subject: String, message: String) -> Result<String, String> {
-    let smtp_server = env::var("SMTP_SERVER").unwrap();
-    let username = env::var("NO_REPLY_EMAIL").unwrap();
-    let password = env::var("NO_REPLY_PASSWORD").unwrap();
+pub fn send_email(
+    config: &Config,
+    to: String,
+    subject: String,
+    message: String,
+) -> Result<String, String> {
+    let smtp = match &config.smtp {
+        Some(smtp) => smtp,
+        None => return Err(String::from("Email is disabled, not sending email")),
+    };
+
     let email = match EmailBuilder::new()
         .to(to.as_str())
-        .from(username.clone())
+        .from(smtp.username.clone())
         .subject(subject.as_str())
         .html(message.as_str())
         .build()
@@ -18,8 +25,8 @@ pub fn send_email(to: String, subject: String, message: String) -> Result<String
         Err(_) => return Err(String::from("Couldn't generate email")),
     };
 
-    let credentials = (username, password).into_credentials();
-    let mut client = match SmtpClient::new_simple(smtp_server.as_str()) {
+    let credentials = (smtp.username.clone(), smtp.password.clone()).into_credentials();
+    let mut client = match SmtpClient::new_simple(smtp.server.as_str()) {
         Ok(client) => client.credentials(credentials).transport(),
         Err(_) => return Err(String::from("Couldn't connect to smtp client")),
     };
@@ -32,19 +39,21 @@ pub fn send_email(to: String, subject: String, message: String) -> Result<String
 
 #[macro_export]
 macro_rules! send_email_using_file {
-    ($to:expr, $subject:expr, $file_path:expr, $alt_message:expr) => {
+    ($config:expr, $to:expr, $subject:expr, $file_path:expr, $alt_message:expr) => {
         match send_email(
+            $config,
             String::from($to),
             String::from($subject),
             read_to_string($file_path).unwrap_or_else(|_| String::from($alt_message)),
         ) {
             Ok(_) => info!("Email sent successfully"),
-            Err(_) => info!("Error sending email"),
+            Err(err) => warn!("{}", err),
         }
     };
 
-    ($to:expr, $subject:expr, $file_path:expr, $alt_message:expr, $format_replacement:expr) => {
+    ($config:expr, $to:expr, $subject:expr, $file_path:expr, $alt_message:expr, $format_replacement:expr) => {
         match send_email(
+            $config,
             String::from($to),
             String::from($subject),
             read_to_string($file_path)
@@ -52,7 +61,7 @@ macro_rules! send_email_using_file {
                 .replace("{}", $format_replacement),
         ) {
             Ok(_) => info!("Email sent successfully"),
-            Err(_) => info!("Error sending email"),
+            Err(err) => warn!("{}", err),
         }
     };
 }
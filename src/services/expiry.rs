@@ -0,0 +1,78 @@
+use crate::config::Config;
+use crate::models::alerts::Alert;
+use crate::models::database::{PGConnection, PGPool};
+use crate::models::error::ApiError;
+use crate::models::notification::{AlertEvent, OutboxNotification};
+use std::thread;
+
+/// Starts the background job that resolves alerts once their expiry passes and lets their
+/// recipients know. The same job queues the notifications of planned events once they are
+/// about to start.
+pub fn start_expiry_job(config: &Config, pool: &PGPool) {
+    let interval = config.alerts.expiry_interval;
+    let config = config.clone();
+    let pool = pool.clone();
+
+    thread::Builder::new()
+        .name(String::from("alert-expiry"))
+        .spawn(move || run_job(&config, &pool))
+        .expect("Couldn't start alert expiry job");
+
+    info!(
+        "Started alert expiry job, running every {} second(s)",
+        interval.as_secs()
+    );
+}
+
+fn run_job(config: &Config, pool: &PGPool) {
+    loop {
+        match resolve_expired(config, pool) {
+            Ok(count) if count > 0 => info!("Resolved {} expired alert(s)", count),
+            Ok(_) => (),
+            Err(err) => error!("Alert expiry job failed: {}", err),
+        }
+
+        match announce_scheduled(config, pool) {
+            Ok(count) if count > 0 => info!("Announced {} upcoming event(s)", count),
+            Ok(_) => (),
+            Err(err) => error!("Announcing upcoming events failed: {}", err),
+        }
+
+        thread::sleep(config.alerts.expiry_interval);
+    }
+}
+
+fn resolve_expired(config: &Config, pool: &PGPool) -> Result<usize, String> {
+    let mut connection = PGConnection::from_pool(pool)?;
+    let mut transaction = connection.transaction().map_err(|err| err.to_string())?;
+
+    let alerts = Alert::resolve_expired(&mut transaction).map_err(|err| err.to_string())?;
+    for alert in &alerts {
+        // Planned events expire when they end
+        let event = match alert.ends_at {
+            Some(_) => AlertEvent::Ended,
+            None => AlertEvent::Expired,
+        };
+
+        OutboxNotification::enqueue_follow_up(alert, event, config, &mut transaction)
+            .map_err(|err: ApiError| err.to_string())?;
+    }
+
+    transaction.commit().map_err(|err| err.to_string())?;
+    Ok(alerts.len())
+}
+
+fn announce_scheduled(config: &Config, pool: &PGPool) -> Result<usize, String> {
+    let mut connection = PGConnection::from_pool(pool)?;
+    let mut transaction = connection.transaction().map_err(|err| err.to_string())?;
+
+    let alerts = Alert::claim_due_announcements(&config.alerts, &mut transaction)
+        .map_err(|err| err.to_string())?;
+    for alert in &alerts {
+        OutboxNotification::enqueue_for_new_recipients(alert, config, &mut transaction)
+            .map_err(|err: ApiError| err.to_string())?;
+    }
+
+    transaction.commit().map_err(|err| err.to_string())?;
+    Ok(alerts.len())
+}
@@ -1,81 +1,527 @@
-use crate::models::alerts::{Alert, AlertNotificationInfo};
+use crate::config::FirebaseConfig;
+use crate::models::notification::OutboxNotification;
+use chrono::Utc;
+use openssl::hash::MessageDigest;
+use openssl::sign::Signer;
 use reqwest::blocking::Client;
-use reqwest::Error;
+use reqwest::StatusCode;
 use rocket_contrib::json::JsonValue;
 use serde::{Deserialize, Serialize};
-use std::{env, thread, time};
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Serialize, Deserialize)]
-struct FirebaseResponseMessageId {
-    message_id: Option<String>,
-    error: Option<String>,
+const MESSAGING_SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+
+// Access tokens are refreshed this long before Google says they expire
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize)]
+struct ServiceAccountClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct AccessTokenResponse {
+    access_token: String,
+    expires_in: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct FirebaseMultiCastResponse {
-    multicast_id: i64,
-    success: i64,
-    failure: i64,
-    canonical_ids: i64,
-    results: Vec<FirebaseResponseMessageId>,
+#[derive(Debug, Deserialize)]
+struct FieldViolation {
+    field: Option<String>,
 }
 
-pub fn send_alert_notification(
-    alert: &Alert,
-    notification_info: Vec<AlertNotificationInfo>,
-) -> u16 {
-    let api_key = env::var("FIREBASE_MESSAGING_SERVER_KEY");
-
-    if let Ok(api_key) = api_key {
-        let client = Client::new();
-        let url = String::from("https://fcm.googleapis.com/fcm/send");
-
-        let payloads = notification_info
-            .iter()
-            .map(|info| {
-                json!({
-                    "data": {
-                        "title": format!("Elevate {} Alert", &alert.alert_type),
-                        "message": format!("{} reported near {:#?}! About {:.1} miles away", &alert.alert_type, &alert.place, &info.distance)
-                    },
-                    "to": info.token,
-                    "priority": 10
-                })
+#[derive(Debug, Deserialize)]
+struct FcmErrorDetail {
+    #[serde(rename = "errorCode")]
+    error_code: Option<String>,
+    #[serde(rename = "fieldViolations")]
+    #[serde(default)]
+    field_violations: Vec<FieldViolation>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FcmError {
+    status: Option<String>,
+    message: Option<String>,
+    #[serde(default)]
+    details: Vec<FcmErrorDetail>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FcmErrorResponse {
+    error: FcmError,
+}
+
+impl FcmError {
+    // INVALID_ARGUMENT also covers malformed messages, only the token's own errors count
+    fn is_unregistered(&self) -> bool {
+        let invalid_token = self.details.iter().any(|detail| {
+            detail
+                .field_violations
+                .iter()
+                .any(|violation| violation.field.as_deref() == Some("message.token"))
+        }) || self.message.as_deref().map_or(false, |message| {
+            message.to_lowercase().contains("registration token")
+        });
+
+        match self.status.as_deref() {
+            Some("NOT_FOUND") => true,
+            Some("INVALID_ARGUMENT") if invalid_token => true,
+            _ => self
+                .details
+                .iter()
+                .any(|detail| detail.error_code.as_deref() == Some("UNREGISTERED")),
+        }
+    }
+}
+
+struct AccessToken {
+    token: String,
+    expires_at: Instant,
+}
+
+pub enum SendResult {
+    Delivered,
+    // The token is no longer valid and should be forgotten
+    Unregistered,
+    Failed(String),
+}
+
+/// Client for the FCM HTTP v1 API, shared by the outbox workers so they reuse one OAuth
+/// access token.
+pub struct FcmClient {
+    http: Client,
+    access_token: Mutex<Option<AccessToken>>,
+}
+
+impl FcmClient {
+    pub fn new(timeout: Duration) -> Self {
+        FcmClient {
+            http: Client::builder()
+                .timeout(timeout)
+                .build()
+                .expect("Couldn't build FCM HTTP client"),
+            access_token: Mutex::new(None),
+        }
+    }
+
+    /// Signs a service account JWT as described in Google's OAuth 2.0 server to server flow.
+    fn sign_assertion(firebase: &FirebaseConfig) -> Result<String, String> {
+        let now = Utc::now().timestamp();
+        let header = json!({"alg": "RS256", "typ": "JWT"});
+        let claims = ServiceAccountClaims {
+            iss: &firebase.client_email,
+            scope: MESSAGING_SCOPE,
+            aud: &firebase.token_uri,
+            iat: now,
+            exp: now + 3600,
+        };
+
+        let claims = serde_json::to_string(&claims).map_err(|err| err.to_string())?;
+        let message = format!(
+            "{}.{}",
+            base64::encode_config(header.to_string(), base64::URL_SAFE_NO_PAD),
+            base64::encode_config(claims, base64::URL_SAFE_NO_PAD)
+        );
+
+        let signature = Signer::new(MessageDigest::sha256(), &firebase.private_key)
+            .and_then(|mut signer| {
+                signer.update(message.as_bytes())?;
+                signer.sign_to_vec()
             })
-            .collect::<Vec<JsonValue>>();
-
-        debug!("Notification payloads: {:#?}", payloads);
-
-        let mut count: u16 = 0;
-
-        for payload in payloads {
-            let response = client
-                .post(&url)
-                .json(&payload)
-                .bearer_auth(&api_key)
-                .send();
-            match response {
-                Ok(response) => {
-                    let response_json: Result<FirebaseMultiCastResponse, Error> = response.json();
-                    match response_json {
-                        Ok(fmc_response) => {
-                            info!("Multicast {} was successful", fmc_response.multicast_id);
-                            count += 1;
-                        }
-                        Err(_) => {
-                            error!("Failed to send notification.");
-                        }
-                    }
+            .map_err(|err| err.to_string())?;
+
+        Ok(format!(
+            "{}.{}",
+            message,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    /// Returns the cached access token, exchanging a fresh assertion for one once it expires.
+    fn access_token(&self, firebase: &FirebaseConfig) -> Result<String, String> {
+        let mut cached = self.access_token.lock().map_err(|err| err.to_string())?;
+
+        if let Some(access_token) = cached.as_ref() {
+            if access_token.expires_at > Instant::now() {
+                return Ok(access_token.token.clone());
+            }
+        }
+
+        let assertion = Self::sign_assertion(firebase)?;
+        let response = self
+            .http
+            .post(&firebase.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", assertion.as_str()),
+            ])
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json::<AccessTokenResponse>())
+            .map_err(|err| format!("Could not obtain FCM access token: {}", err))?;
+
+        let lifetime = Duration::from_secs(response.expires_in);
+        *cached = Some(AccessToken {
+            token: response.access_token.clone(),
+            expires_at: Instant::now()
+                + lifetime
+                    .checked_sub(TOKEN_EXPIRY_MARGIN)
+                    .unwrap_or_default(),
+        });
+
+        Ok(response.access_token)
+    }
+
+    fn forget_access_token(&self) {
+        if let Ok(mut cached) = self.access_token.lock() {
+            *cached = None;
+        }
+    }
+
+    pub fn send(&self, firebase: &FirebaseConfig, message: &JsonValue) -> SendResult {
+        let access_token = match self.access_token(firebase) {
+            Ok(access_token) => access_token,
+            Err(err) => return SendResult::Failed(err),
+        };
+
+        let url = format!(
+            "{}/v1/projects/{}/messages:send",
+            firebase.base_url, firebase.project_id
+        );
+
+        let response = match self
+            .http
+            .post(&url)
+            .bearer_auth(access_token)
+            .json(message)
+            .send()
+        {
+            Ok(response) => response,
+            Err(err) => return SendResult::Failed(err.to_string()),
+        };
+
+        let status = response.status();
+        if status.is_success() {
+            return SendResult::Delivered;
+        }
+
+        if status == StatusCode::UNAUTHORIZED {
+            // The token was revoked early, fetch a new one for the next message
+            self.forget_access_token();
+        }
+
+        match response.json::<FcmErrorResponse>() {
+            Ok(FcmErrorResponse { error }) => {
+                if error.is_unregistered() {
+                    return SendResult::Unregistered;
                 }
-                Err(msg) => {
-                    error!("{}", msg);
+
+                SendResult::Failed(format!("{}: {}", status, error.message.unwrap_or_default()))
+            }
+            Err(_) => SendResult::Failed(status.to_string()),
+        }
+    }
+}
+
+/// Builds the v1 message for a queued notification. Title and body are repeated in the data
+/// payload for app versions that still read them from there.
+pub fn build_message(notification: &OutboxNotification) -> JsonValue {
+    let mut data = notification.data.clone();
+    if let Value::Object(fields) = &mut data {
+        fields.insert(String::from("title"), json!(notification.title).0);
+        fields.insert(String::from("message"), json!(notification.body).0);
+    }
+
+    let high_priority = notification.push_priority == "high";
+
+    let mut android_notification = json!({});
+    if let Some(channel) = &notification.android_channel {
+        android_notification["channel_id"] = json!(channel).0;
+    }
+    if let Some(sound) = &notification.sound {
+        android_notification["sound"] = json!(sound).0;
+    }
+
+    let mut aps = json!({});
+    if let Some(sound) = &notification.sound {
+        aps["sound"] = json!(sound).0;
+    }
+
+    json!({
+        "message": {
+            "token": notification.token,
+            "notification": {
+                "title": notification.title,
+                "body": notification.body
+            },
+            "data": data,
+            "android": {
+                "priority": if high_priority { "high" } else { "normal" },
+                "notification": android_notification
+            },
+            "apns": {
+                "headers": {
+                    "apns-priority": if high_priority { "10" } else { "5" }
+                },
+                "payload": {
+                    "aps": aps
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FirebaseConfig;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    const SEND_PATH: &str = "/v1/projects/test-project/messages:send";
+
+    /// Stand-in for Google's token endpoint and FCM, answering each request with the next of
+    /// `responses` and recording the path and authorization header it was sent with.
+    fn stand_in(responses: Vec<(u16, &'static str)>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let path = request_line
+                    .split(' ')
+                    .nth(1)
+                    .unwrap_or_default()
+                    .to_string();
+
+                let mut authorization = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let header = header.trim_end();
+                    if header.is_empty() {
+                        break;
+                    }
+
+                    let (name, value) = header.split_once(':').unwrap();
+                    match name.to_lowercase().as_str() {
+                        "authorization" => authorization = value.trim().to_string(),
+                        "content-length" => content_length = value.trim().parse().unwrap(),
+                        _ => (),
+                    }
                 }
-            };
-            thread::sleep(time::Duration::from_millis(100));
+                reader
+                    .by_ref()
+                    .take(content_length)
+                    .read_to_end(&mut Vec::new())
+                    .unwrap();
+
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", path, authorization).trim().to_string());
+
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {} Stand-in\r\nContent-Type: application/json\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn notification(push_priority: &str) -> OutboxNotification {
+        OutboxNotification {
+            id: 1,
+            alert_id: Some(2),
+            user_id: 3,
+            token: String::from("device"),
+            title: String::from("Theft reported"),
+            body: String::from("A Theft was reported near Main Street"),
+            data: json!({"alertId": "2", "event": "created"}).0,
+            attempts: 1,
+            push_priority: String::from(push_priority),
+            sound: Some(String::from("alarm")),
+            android_channel: Some(String::from("alerts")),
+        }
+    }
+
+    fn access_token(token: &'static str) -> (u16, &'static str) {
+        match token {
+            "first" => (200, r#"{"access_token": "first", "expires_in": 3600}"#),
+            _ => (200, r#"{"access_token": "second", "expires_in": 3600}"#),
+        }
+    }
+
+    #[test]
+    fn access_tokens_are_reused_until_rejected() {
+        let (url, requests) = stand_in(vec![
+            access_token("first"),
+            (200, "{}"),
+            (200, "{}"),
+            (
+                401,
+                r#"{"error": {"code": 401, "status": "UNAUTHENTICATED", "message": "Invalid credentials"}}"#,
+            ),
+            access_token("second"),
+            (200, "{}"),
+        ]);
+        let firebase = FirebaseConfig::for_tests(&url);
+        let fcm = FcmClient::new(Duration::from_secs(5));
+        let message = build_message(&notification("high"));
+        assert!(matches!(
+            fcm.send(&firebase, &message),
+            SendResult::Delivered
+        ));
+        assert!(matches!(
+            fcm.send(&firebase, &message),
+            SendResult::Delivered
+        ));
+        assert!(matches!(
+            fcm.send(&firebase, &message),
+            SendResult::Failed(_)
+        ));
+        assert!(matches!(
+            fcm.send(&firebase, &message),
+            SendResult::Delivered
+        ));
+
+        let send = |token| format!("{} Bearer {}", SEND_PATH, token);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                String::from("/token"),
+                send("first"),
+                send("first"),
+                send("first"),
+                String::from("/token"),
+                send("second"),
+            ]
+        );
+    }
+
+    #[test]
+    fn send_results_follow_fcm_errors() {
+        let (url, _) = stand_in(vec![
+            access_token("first"),
+            (
+                404,
+                r#"{"error": {"code": 404, "status": "NOT_FOUND", "message": "Requested entity was not found.",
+                    "details": [{"@type": "type.googleapis.com/google.firebase.fcm.v1.FcmError",
+                        "errorCode": "UNREGISTERED"}]}}"#,
+            ),
+            (
+                503,
+                r#"{"error": {"code": 503, "status": "UNAVAILABLE", "message": "The service is unavailable."}}"#,
+            ),
+        ]);
+        let firebase = FirebaseConfig::for_tests(&url);
+        let fcm = FcmClient::new(Duration::from_secs(5));
+        let message = build_message(&notification("normal"));
+
+        assert!(matches!(
+            fcm.send(&firebase, &message),
+            SendResult::Unregistered
+        ));
+        match fcm.send(&firebase, &message) {
+            SendResult::Failed(reason) => assert!(reason.contains("503"), "{}", reason),
+            _ => panic!("A 503 should be retried"),
         }
+    }
+
+    #[test]
+    fn messages_use_the_v1_android_and_apns_blocks() {
+        let high = build_message(&notification("high")).0;
+        assert_eq!(high["message"]["token"], "device");
+        assert_eq!(high["message"]["notification"]["title"], "Theft reported");
+        assert_eq!(high["message"]["data"]["alertId"], "2");
+        assert_eq!(high["message"]["data"]["title"], "Theft reported");
+        assert_eq!(
+            high["message"]["data"]["message"],
+            "A Theft was reported near Main Street"
+        );
+        assert_eq!(high["message"]["android"]["priority"], "high");
+        assert_eq!(
+            high["message"]["android"]["notification"]["channel_id"],
+            "alerts"
+        );
+        assert_eq!(high["message"]["android"]["notification"]["sound"], "alarm");
+        assert_eq!(high["message"]["apns"]["headers"]["apns-priority"], "10");
+        assert_eq!(high["message"]["apns"]["payload"]["aps"]["sound"], "alarm");
+
+        let normal = build_message(&notification("normal")).0;
+        assert_eq!(normal["message"]["android"]["priority"], "normal");
+        assert_eq!(normal["message"]["apns"]["headers"]["apns-priority"], "5");
+    }
 
-        return count;
+    fn error(body: &str) -> FcmError {
+        serde_json::from_str::<FcmErrorResponse>(body)
+            .unwrap()
+            .error
     }
 
-    return 0u16;
+    #[test]
+    fn unregistered_and_invalid_tokens_are_unregistered() {
+        assert!(error(
+            r#"{"error": {"code": 404, "status": "NOT_FOUND", "message": "Requested entity was not found.",
+                "details": [{"@type": "type.googleapis.com/google.firebase.fcm.v1.FcmError",
+                    "errorCode": "UNREGISTERED"}]}}"#
+        )
+        .is_unregistered());
+
+        assert!(error(
+            r#"{"error": {"code": 400, "status": "INVALID_ARGUMENT",
+                "message": "The registration token is not a valid FCM registration token",
+                "details": [{"@type": "type.googleapis.com/google.firebase.fcm.v1.FcmError",
+                    "errorCode": "INVALID_ARGUMENT"}]}}"#
+        )
+        .is_unregistered());
+
+        assert!(error(
+            r#"{"error": {"code": 400, "status": "INVALID_ARGUMENT", "message": "Invalid value",
+                "details": [{"@type": "type.googleapis.com/google.rpc.BadRequest",
+                    "fieldViolations": [{"field": "message.token",
+                        "description": "Invalid registration token"}]}]}}"#
+        )
+        .is_unregistered());
+    }
+
+    #[test]
+    fn other_errors_are_retried() {
+        assert!(!error(
+            r#"{"error": {"code": 400, "status": "INVALID_ARGUMENT",
+                "message": "Invalid value at 'message.data[0].value' (TYPE_STRING), 1",
+                "details": [{"@type": "type.googleapis.com/google.rpc.BadRequest",
+                    "fieldViolations": [{"field": "message.data[0].value"}]}]}}"#
+        )
+        .is_unregistered());
+
+        assert!(!error(
+            r#"{"error": {"code": 503, "status": "UNAVAILABLE", "message": "The service is unavailable."}}"#
+        )
+        .is_unregistered());
+    }
 }
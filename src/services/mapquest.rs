@@ -1,21 +1,20 @@
+use crate::config::Config;
 use reqwest::blocking::Client;
 use serde_json::Value;
-use std::env;
 use urlencoding::encode;
 
 pub enum MapquestResult<T> {
-    NoAPIKey,
+    Disabled,
+    RequestFailed,
     NoResult,
     NoValue,
     Success(T),
 }
 
-pub fn get_address(latitude: f32, longitude: f32) -> MapquestResult<String> {
-    // Grab api key from environment
-    let api_key = env::var("MAPQUEST_API_KEY");
-
-    // If api key was found, do request
-    if let Ok(api_key) = api_key {
+pub fn get_address(latitude: f32, longitude: f32, config: &Config) -> MapquestResult<String> {
+    // Geocoding is disabled when no api key is configured
+    if let Some(mapquest) = &config.mapquest {
+        let api_key = &mapquest.api_key;
         let client = Client::new();
 
         // Put lat-long location into correct format
@@ -56,18 +55,17 @@ pub fn get_address(latitude: f32, longitude: f32) -> MapquestResult<String> {
                 }
             }
         }
+
+        return MapquestResult::RequestFailed;
     }
 
-    // No api key found
-    MapquestResult::NoAPIKey
+    MapquestResult::Disabled
 }
 
-pub fn get_location(place: String) -> MapquestResult<(f32, f32)> {
-    // Grab api key from environment
-    let api_key = env::var("MAPQUEST_API_KEY");
-
-    // If api key was found, do request
-    if let Ok(api_key) = api_key {
+pub fn get_location(place: String, config: &Config) -> MapquestResult<(f32, f32)> {
+    // Geocoding is disabled when no api key is configured
+    if let Some(mapquest) = &config.mapquest {
+        let api_key = &mapquest.api_key;
         let client = Client::new();
 
         // Build request url
@@ -103,8 +101,9 @@ pub fn get_location(place: String) -> MapquestResult<(f32, f32)> {
                 }
             }
         }
+
+        return MapquestResult::RequestFailed;
     }
 
-    // No api key found
-    MapquestResult::NoAPIKey
+    MapquestResult::Disabled
 }
@@ -1,3 +1,6 @@
 pub mod email;
+pub mod expiry;
 pub mod firebase;
 pub mod mapquest;
+pub mod outbox;
+pub mod retention;
@@ -0,0 +1,99 @@
+use crate::config::{Config, FirebaseConfig, NotificationConfig};
+use crate::models::database::{PGConnection, PGPool};
+use crate::models::device::DeviceToken;
+use crate::models::error::ApiError;
+use crate::models::notification::OutboxNotification;
+use crate::services::firebase::{build_message, FcmClient, SendResult};
+use std::sync::Arc;
+use std::thread;
+
+/// Starts the background workers that drain the notification outbox. Each worker delivers one
+/// batch at a time, so at most `NOTIFICATION_WORKERS` messages are in flight at once.
+pub fn start_workers(config: &Config, pool: &PGPool) {
+    let firebase = match &config.firebase {
+        Some(firebase) => firebase,
+        None => {
+            warn!("Firebase is disabled, not starting notification workers");
+            return;
+        }
+    };
+
+    let fcm = Arc::new(FcmClient::new(config.notifications.send_timeout));
+
+    for worker in 0..config.notifications.workers {
+        let firebase = firebase.clone();
+        let settings = config.notifications.clone();
+        let fcm = fcm.clone();
+        let pool = pool.clone();
+
+        thread::Builder::new()
+            .name(format!("notification-worker-{}", worker))
+            .spawn(move || run_worker(&firebase, &settings, &fcm, &pool))
+            .expect("Couldn't start notification worker");
+    }
+
+    info!(
+        "Started {} notification worker(s)",
+        config.notifications.workers
+    );
+}
+
+fn run_worker(
+    firebase: &FirebaseConfig,
+    settings: &NotificationConfig,
+    fcm: &FcmClient,
+    pool: &PGPool,
+) {
+    loop {
+        match deliver_batch(firebase, settings, fcm, pool) {
+            // Keep going while there is a backlog
+            Ok(count) if count > 0 => continue,
+            Ok(_) => (),
+            Err(err) => error!("Notification worker failed: {}", err),
+        }
+
+        thread::sleep(settings.poll_interval);
+    }
+}
+
+fn deliver_batch(
+    firebase: &FirebaseConfig,
+    settings: &NotificationConfig,
+    fcm: &FcmClient,
+    pool: &PGPool,
+) -> Result<usize, String> {
+    let notifications = {
+        let mut connection = PGConnection::from_pool(pool)?;
+        let mut transaction = connection.transaction().map_err(|err| err.to_string())?;
+        let notifications = OutboxNotification::claim_due(settings, &mut transaction);
+        transaction.commit().map_err(|err| err.to_string())?;
+        notifications
+    };
+
+    // The connection goes back to the pool while each message is sent
+    for notification in &notifications {
+        let result = fcm.send(firebase, &build_message(notification));
+
+        let mut connection = PGConnection::from_pool(pool)?;
+        let mut transaction = connection.transaction().map_err(|err| err.to_string())?;
+        let recorded = match result {
+            SendResult::Delivered => notification.mark_sent(&mut transaction),
+            SendResult::Unregistered => notification
+                .mark_dead("Device token is no longer registered", &mut transaction)
+                .and_then(|_| DeviceToken::remove(&notification.token, &mut transaction))
+                .map(|_| info!("Pruned unregistered device token")),
+            SendResult::Failed(reason) => {
+                warn!(
+                    "Notification {} failed on attempt {}: {}",
+                    notification.id, notification.attempts, reason
+                );
+                notification.mark_failed(&reason, settings, &mut transaction)
+            }
+        };
+
+        recorded.map_err(|err: ApiError| err.to_string())?;
+        transaction.commit().map_err(|err| err.to_string())?;
+    }
+
+    Ok(notifications.len())
+}
@@ -0,0 +1,54 @@
+use crate::config::Config;
+use crate::models::database::{PGConnection, PGPool};
+use crate::models::location::Location;
+use std::thread;
+use std::time::Duration;
+
+// Retention is counted in days, pruning more often than hourly gains nothing
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Starts the background job that deletes location history older than the retention period.
+pub fn start_pruning_job(config: &Config, pool: &PGPool) {
+    let retention_days = match config.locations.history_retention_days {
+        Some(days) => days,
+        None => {
+            info!("Location history is kept forever, not starting pruning job");
+            return;
+        }
+    };
+
+    let pool = pool.clone();
+
+    thread::Builder::new()
+        .name(String::from("location-pruning"))
+        .spawn(move || run_job(retention_days, &pool))
+        .expect("Couldn't start location pruning job");
+
+    info!(
+        "Started location pruning job, keeping {} day(s) of history",
+        retention_days
+    );
+}
+
+fn run_job(retention_days: i32, pool: &PGPool) {
+    loop {
+        match prune(retention_days, pool) {
+            Ok(count) if count > 0 => info!("Pruned {} location history point(s)", count),
+            Ok(_) => (),
+            Err(err) => error!("Location pruning job failed: {}", err),
+        }
+
+        thread::sleep(PRUNE_INTERVAL);
+    }
+}
+
+fn prune(retention_days: i32, pool: &PGPool) -> Result<u64, String> {
+    let mut connection = PGConnection::from_pool(pool)?;
+    let mut transaction = connection.transaction().map_err(|err| err.to_string())?;
+
+    let count =
+        Location::prune_history(retention_days, &mut transaction).map_err(|err| err.to_string())?;
+
+    transaction.commit().map_err(|err| err.to_string())?;
+    Ok(count)
+}
@@ -1,17 +1,31 @@
-use crate::models::alerts::{Alert, AlertType};
+use crate::config::Config;
+use crate::models::alerts::{
+    Alert, AlertCluster, AlertCursor, AlertFilters, AlertSort, AlertStatus, AlertType,
+};
 use crate::models::auth::{BearerToken, TokenType};
+use crate::models::block::BlockedUser;
 use crate::models::database::PGConnection;
+use crate::models::error::ApiError;
+use crate::models::geo::{is_valid_coordinate, DistanceUnit};
+use crate::models::notification::{AlertEvent, OutboxNotification};
+use crate::models::track::TrackPoint;
 use crate::models::user::User;
-use crate::services::firebase::send_alert_notification;
 use crate::views::request::StandardResponse;
 use crate::{fetch_user, transaction};
+use chrono::{DateTime, NaiveDateTime};
 use rocket::http::Status;
+use rocket::request::LenientForm;
+use rocket::State;
 use rocket_contrib::json::Json;
 
 #[get("/types")]
-pub fn get_alert_types(token: BearerToken, mut connection: PGConnection) -> StandardResponse {
+pub fn get_alert_types(
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
     let mut transaction = transaction!(connection);
-    fetch_user!(token.token, TokenType::Auth, &mut transaction);
+    fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
 
     StandardResponse {
         status: Status::Ok,
@@ -23,47 +37,49 @@ pub fn get_alert_types(token: BearerToken, mut connection: PGConnection) -> Stan
 pub fn create_alert(
     alert: Json<Alert>,
     token: BearerToken,
+    config: State<Config>,
     mut connection: PGConnection,
 ) -> StandardResponse {
     let mut transaction = transaction!(connection);
-    let user = fetch_user!(token.token, TokenType::Auth, &mut transaction);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
 
     let mut alert = alert.into_inner();
     alert.created_by = user.email;
 
-    let alert = match alert.init(&mut transaction) {
+    let alert = match alert.init(&config, &mut transaction) {
         Ok(alert) => alert,
-        Err(err) => {
-            return StandardResponse {
-                status: Status::UnprocessableEntity,
-                response: json!({ "message": format!("Could not create new alert: {}", err) }),
-            }
-        }
+        Err(err) => return err.into(),
     };
 
-    let notification_info = alert.get_notification_info(&mut transaction);
+    // Notifications are queued with the alert and delivered by the outbox workers, planned
+    // events are announced by the expiry job shortly before they start
+    let message = if !alert.is_notification_due(&config.alerts) {
+        String::from(
+            "Alert successfully scheduled, nearby devices are notified shortly before it starts",
+        )
+    } else {
+        match OutboxNotification::enqueue_for_new_recipients(&alert, &config, &mut transaction) {
+            Ok(Some(count)) => format!(
+                "Alert successfully created, notifying {} nearby device(s)",
+                count
+            ),
+            Ok(None) => String::from("Alert successfully created, push notifications are disabled"),
+            Err(err) => return err.into(),
+        }
+    };
 
     match transaction.commit() {
-        Ok(_) => {
-            let count = send_alert_notification(&alert, notification_info);
-
-            StandardResponse {
-                status: Status::Ok,
-                response: json!({
-                    "message": format!("Alert successfully created, notified {} nearby user(s)", count),
-                    "alert": alert
-                }),
-            }
-        }
+        Ok(_) => StandardResponse {
+            status: Status::Ok,
+            response: json!({
+                "message": message,
+                "alert": alert
+            }),
+        },
 
         Err(err) => {
             error!("{}", err);
-            StandardResponse {
-                status: Status::ServiceUnavailable,
-                response: json!({
-                    "message": "Unable to commit changes to database"
-                }),
-            }
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
         }
     }
 }
@@ -73,44 +89,44 @@ pub fn update_alert(
     alert_id: i64,
     updated: Json<Alert>,
     token: BearerToken,
+    config: State<Config>,
     mut connection: PGConnection,
 ) -> StandardResponse {
     let mut transaction = transaction!(connection);
-    let user = fetch_user!(token.token, TokenType::Auth, &mut transaction);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
 
     let alert = match Alert::get_by_id(alert_id, &mut transaction) {
         Some(alert) => alert,
         None => {
-            return StandardResponse {
-                status: Status::BadRequest,
-                response: json!({
-                    "message": format!("Could not find alert with id {}", alert_id)
-                }),
-            }
+            return ApiError::NotFound(format!("Could not find alert with id {}", alert_id)).into()
         }
     };
 
     if alert.created_by != user.email {
-        return StandardResponse {
-            status: Status::BadRequest,
-            response: json!({
-                "message": "You cannot edit an alert you did not create"
-            }),
-        };
+        return ApiError::Forbidden(String::from("You cannot edit an alert you did not create"))
+            .into();
     }
 
     let mut updated = updated.into_inner();
 
-    let updated = match alert.update(&mut updated, &mut transaction) {
+    let updated = match alert.update(&mut updated, &config, &mut transaction) {
         Ok(alert) => alert,
-        Err(err) => {
-            return StandardResponse {
-                status: Status::UnprocessableEntity,
-                response: json!({ "message": format!("Could not update alert: {}", err) }),
-            }
-        }
+        Err(err) => return err.into(),
     };
 
+    // Let everyone who saw the alert know, then reach whoever the new location puts in range
+    if let Err(err) = OutboxNotification::enqueue_follow_up(
+        &updated,
+        AlertEvent::Updated,
+        &config,
+        &mut transaction,
+    )
+    .and_then(|_| {
+        OutboxNotification::enqueue_for_new_recipients(&updated, &config, &mut transaction)
+    }) {
+        return err.into();
+    }
+
     match transaction.commit() {
         Ok(_) => StandardResponse {
             status: Status::Ok,
@@ -122,12 +138,7 @@ pub fn update_alert(
 
         Err(err) => {
             error!("{}", err);
-            StandardResponse {
-                status: Status::ServiceUnavailable,
-                response: json!({
-                    "message": "Unable to commit changes to database"
-                }),
-            }
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
         }
     }
 }
@@ -136,44 +147,38 @@ pub fn update_alert(
 pub fn resolve_alert(
     alert_id: i64,
     token: BearerToken,
+    config: State<Config>,
     mut connection: PGConnection,
 ) -> StandardResponse {
     let mut transaction = transaction!(connection);
-    let user = fetch_user!(token.token, TokenType::Auth, &mut transaction);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
 
     let alert = match Alert::get_by_id(alert_id, &mut transaction) {
         Some(alert) => alert,
         None => {
-            return StandardResponse {
-                status: Status::BadRequest,
-                response: json!({
-                    "message": format!("Could not find alert with id {}", alert_id)
-                }),
-            }
+            return ApiError::NotFound(format!("Could not find alert with id {}", alert_id)).into()
         }
     };
 
     if alert.created_by != user.email {
-        return StandardResponse {
-            status: Status::BadRequest,
-            response: json!({
-                "message": "You cannot edit an alert you did not create"
-            }),
-        };
+        return ApiError::Forbidden(String::from("You cannot edit an alert you did not create"))
+            .into();
     }
 
     let alert = match alert.resolve(&mut transaction) {
         Ok(alert) => alert,
-        Err(_) => {
-            return StandardResponse {
-                status: Status::UnprocessableEntity,
-                response: json!({
-                    "message": "Couldn't mark alert as resolved"
-                }),
-            }
-        }
+        Err(err) => return err.into(),
     };
 
+    if let Err(err) = OutboxNotification::enqueue_follow_up(
+        &alert,
+        AlertEvent::Resolved,
+        &config,
+        &mut transaction,
+    ) {
+        return err.into();
+    }
+
     match transaction.commit() {
         Ok(_) => StandardResponse {
             status: Status::Ok,
@@ -185,12 +190,7 @@ pub fn resolve_alert(
 
         Err(err) => {
             error!("{}", err);
-            StandardResponse {
-                status: Status::ServiceUnavailable,
-                response: json!({
-                    "message": "Unable to commit changes to database"
-                }),
-            }
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
         }
     }
 }
@@ -199,39 +199,36 @@ pub fn resolve_alert(
 pub fn delete_alert(
     alert_id: i64,
     token: BearerToken,
+    config: State<Config>,
     mut connection: PGConnection,
 ) -> StandardResponse {
     let mut transaction = transaction!(connection);
-    let user = fetch_user!(token.token, TokenType::Auth, &mut transaction);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
 
     let alert = match Alert::get_by_id(alert_id, &mut transaction) {
         Some(alert) => alert,
         None => {
-            return StandardResponse {
-                status: Status::BadRequest,
-                response: json!({
-                    "message": format!("Could not find alert with id {}", alert_id)
-                }),
-            }
+            return ApiError::NotFound(format!("Could not find alert with id {}", alert_id)).into()
         }
     };
 
     if alert.created_by != user.email {
-        return StandardResponse {
-            status: Status::BadRequest,
-            response: json!({
-                "message": "You cannot edit an alert you did not create"
-            }),
-        };
+        return ApiError::Forbidden(String::from("You cannot edit an alert you did not create"))
+            .into();
     }
 
-    if alert.delete(&mut transaction).is_err() {
-        return StandardResponse {
-            status: Status::UnprocessableEntity,
-            response: json!({
-                "message": "Could not delete alert"
-            }),
-        };
+    // Queued before the delete, which would otherwise take the recipients with it
+    if let Err(err) = OutboxNotification::enqueue_follow_up(
+        &alert,
+        AlertEvent::Retracted,
+        &config,
+        &mut transaction,
+    ) {
+        return err.into();
+    }
+
+    if let Err(err) = alert.delete(&mut transaction) {
+        return err.into();
     }
 
     match transaction.commit() {
@@ -245,36 +242,394 @@ pub fn delete_alert(
 
         Err(err) => {
             error!("{}", err);
-            StandardResponse {
-                status: Status::ServiceUnavailable,
-                response: json!({
-                    "message": "Unable to commit changes to database"
-                }),
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
+    }
+}
+
+#[post("/<alert_id>/block")]
+pub fn block_alert_creator(
+    alert_id: i64,
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    let alert = match Alert::get_by_id(alert_id, &mut transaction) {
+        Some(alert) => alert,
+        None => {
+            return ApiError::NotFound(format!("Could not find alert with id {}", alert_id)).into()
+        }
+    };
+
+    if alert.created_by == user.email {
+        return ApiError::validation("alertId", "You cannot block yourself").into();
+    }
+
+    let blocked = match BlockedUser::block_alert_creator(&user, &alert.created_by, &mut transaction)
+    {
+        Ok(blocked) => blocked,
+        Err(err) => return err.into(),
+    };
+
+    match transaction.commit() {
+        Ok(_) => StandardResponse {
+            status: Status::Ok,
+            response: json!({
+                "message": "User blocked successfully",
+                "blockedUser": blocked
+            }),
+        },
+
+        Err(err) => {
+            error!("{}", err);
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
+    }
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+/// The viewport of the alert feed and its optional filters, all given as query parameters.
+#[derive(FromForm)]
+pub struct AlertQuery {
+    lat: f32,
+    lng: f32,
+    lat_delta: f32,
+    lng_delta: f32,
+    // true, false or all, only unresolved alerts by default unless a status is given
+    resolved: Option<String>,
+    // upcoming, active or past
+    status: Option<String>,
+    // Comma separated alert type names
+    types: Option<String>,
+    min_level: Option<i16>,
+    created_after: Option<String>,
+    created_before: Option<String>,
+    // Creator email, or me for your own alerts
+    creator: Option<String>,
+    // newest or oldest, newest first by default
+    sort: Option<String>,
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+fn parse_time(field: &str, value: &Option<String>) -> Result<Option<NaiveDateTime>, ApiError> {
+    match value {
+        Some(value) => DateTime::parse_from_rfc3339(value)
+            .map(|time| time.naive_utc())
+            .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S"))
+            .map(Some)
+            .map_err(|_| ApiError::validation(field, "Must be an RFC 3339 timestamp")),
+        None => Ok(None),
+    }
+}
+
+// Cursors are opaque to clients, one that doesn't decode was tampered with or truncated
+fn parse_cursor(value: &Option<String>) -> Result<Option<AlertCursor>, ApiError> {
+    match value {
+        Some(value) => AlertCursor::decode(value)
+            .map(Some)
+            .ok_or_else(|| ApiError::BadRequest(String::from("Invalid cursor"))),
+        None => Ok(None),
+    }
+}
+
+impl AlertQuery {
+    fn into_filters(self, user: &User) -> Result<AlertFilters, ApiError> {
+        let status = match self.status.as_deref() {
+            None => None,
+            Some("upcoming") => Some(AlertStatus::Upcoming),
+            Some("active") => Some(AlertStatus::Active),
+            Some("past") => Some(AlertStatus::Past),
+            Some(_) => {
+                return Err(ApiError::validation(
+                    "status",
+                    "Must be upcoming, active or past",
+                ))
+            }
+        };
+
+        let resolved = match self.resolved.as_deref() {
+            None if status.is_some() => None,
+            None | Some("false") => Some(false),
+            Some("true") => Some(true),
+            Some("all") => None,
+            Some(_) => {
+                return Err(ApiError::validation(
+                    "resolved",
+                    "Must be true, false or all",
+                ))
+            }
+        };
+
+        let sort = match self.sort.as_deref() {
+            None | Some("newest") => AlertSort::Newest,
+            Some("oldest") => AlertSort::Oldest,
+            Some(_) => return Err(ApiError::validation("sort", "Must be newest or oldest")),
+        };
+
+        if let Some(min_level) = self.min_level {
+            if !(1..=3).contains(&min_level) {
+                return Err(ApiError::validation("min_level", "Must be between 1 and 3"));
             }
         }
+
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+            return Err(ApiError::validation(
+                "limit",
+                &format!("Must be between 1 and {}", MAX_PAGE_SIZE),
+            ));
+        }
+
+        Ok(AlertFilters {
+            resolved,
+            status,
+            alert_types: self.types.map(|types| {
+                types
+                    .split(',')
+                    .map(|alert_type| alert_type.trim().to_string())
+                    .filter(|alert_type| !alert_type.is_empty())
+                    .collect()
+            }),
+            min_level: self.min_level,
+            created_after: parse_time("created_after", &self.created_after)?,
+            created_before: parse_time("created_before", &self.created_before)?,
+            creator: self.creator.map(|creator| match creator.as_str() {
+                "me" => user.email.clone(),
+                _ => creator,
+            }),
+            sort,
+            cursor: parse_cursor(&self.cursor)?,
+            limit,
+        })
     }
 }
 
-#[get("/?<lat>&<lng>&<lat_delta>&<lng_delta>")]
+#[get("/?<query..>")]
 pub fn get_by_viewport(
+    query: LenientForm<AlertQuery>,
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    let query = query.into_inner();
+    let (lat, lng, lat_delta, lng_delta) = (query.lat, query.lng, query.lat_delta, query.lng_delta);
+
+    let filters = match query.into_filters(&user) {
+        Ok(filters) => filters,
+        Err(err) => return err.into(),
+    };
+
+    let (alerts, next_cursor) = Alert::get_by_viewport(
+        lat + lat_delta / 2f32,
+        lng + lng_delta / 2f32,
+        lat - lat_delta / 2f32,
+        lng - lng_delta / 2f32,
+        &filters,
+        &user.email,
+        &mut transaction,
+    );
+
+    StandardResponse {
+        status: Status::Ok,
+        response: json!({
+            "alerts": alerts,
+            "nextCursor": next_cursor.map(|cursor| cursor.encode())
+        }),
+    }
+}
+
+// Clusters smaller than this are sent as individual alerts instead
+const MIN_CLUSTER_SIZE: i64 = 3;
+// Grid cells per map tile width, a tile spans 360 / 2^zoom degrees of longitude
+const CLUSTER_CELLS_PER_TILE: f64 = 4.0;
+const MAX_ZOOM: u8 = 22;
+
+#[derive(FromForm)]
+pub struct ClusterQuery {
     lat: f32,
     lng: f32,
     lat_delta: f32,
     lng_delta: f32,
+    zoom: u8,
+}
+
+#[get("/clusters?<query..>")]
+pub fn get_clusters(
+    query: LenientForm<ClusterQuery>,
     token: BearerToken,
+    config: State<Config>,
     mut connection: PGConnection,
 ) -> StandardResponse {
+    if query.zoom > MAX_ZOOM {
+        return ApiError::validation("zoom", &format!("Must be at most {}", MAX_ZOOM)).into();
+    }
+
     let mut transaction = transaction!(connection);
-    fetch_user!(token.token, TokenType::Auth, &mut transaction);
+    fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    let cell_size = 360.0 / (2f64.powi(i32::from(query.zoom)) * CLUSTER_CELLS_PER_TILE);
+    let (clusters, scattered): (Vec<AlertCluster>, Vec<AlertCluster>) = Alert::get_clusters(
+        query.lat + query.lat_delta / 2f32,
+        query.lng + query.lng_delta / 2f32,
+        query.lat - query.lat_delta / 2f32,
+        query.lng - query.lng_delta / 2f32,
+        cell_size,
+        MIN_CLUSTER_SIZE,
+        &mut transaction,
+    )
+    .into_iter()
+    .partition(|cluster| cluster.count >= MIN_CLUSTER_SIZE);
+
+    let scattered_ids = scattered
+        .into_iter()
+        .flat_map(|cluster| cluster.alert_ids)
+        .collect::<Vec<i64>>();
 
     StandardResponse {
         status: Status::Ok,
-        response: json!(Alert::get_by_viewport(
-            lat + lat_delta / 2f32,
-            lng + lng_delta / 2f32,
-            lat - lat_delta / 2f32,
-            lng - lng_delta / 2f32,
-            &mut transaction
-        )),
+        response: json!({
+            "clusters": clusters,
+            "alerts": Alert::get_by_ids(&scattered_ids, &mut transaction)
+        }),
+    }
+}
+
+// Larger searches would pull in most of the alerts table
+const MAX_NEARBY_RADIUS_MILES: f64 = 100.0;
+
+#[get("/nearby?<lat>&<lng>&<radius>&<unit>")]
+pub fn get_nearby(
+    lat: f64,
+    lng: f64,
+    radius: Option<f64>,
+    unit: Option<DistanceUnit>,
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let unit = unit.unwrap_or(DistanceUnit::Miles);
+    let radius_miles = unit.to_miles(radius.unwrap_or(10.0));
+
+    if !is_valid_coordinate(lat, lng) {
+        return ApiError::validation("lat", "Must be a valid latitude and longitude").into();
+    }
+
+    if !(radius_miles > 0.0 && radius_miles <= MAX_NEARBY_RADIUS_MILES) {
+        return ApiError::validation(
+            "radius",
+            &format!(
+                "Must be positive and at most {:.0} {}",
+                unit.convert_miles(MAX_NEARBY_RADIUS_MILES),
+                unit.abbreviation()
+            ),
+        )
+        .into();
+    }
+
+    let mut transaction = transaction!(connection);
+    fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    let alerts = Alert::get_nearby(lat, lng, radius_miles, &mut transaction)
+        .into_iter()
+        .map(|mut alert| {
+            alert.distance = alert.distance.map(|distance| unit.convert_miles(distance));
+            alert
+        })
+        .collect::<Vec<Alert>>();
+
+    StandardResponse {
+        status: Status::Ok,
+        response: json!(alerts),
+    }
+}
+
+#[get("/<alert_id>/track?<tolerance>")]
+pub fn get_alert_track(
+    alert_id: i64,
+    tolerance: Option<f64>,
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    if let Some(tolerance) = tolerance {
+        if !(tolerance.is_finite() && tolerance > 0.0) {
+            return ApiError::validation("tolerance", "Must be a positive number of meters").into();
+        }
+    }
+
+    let alert = match Alert::get_by_id(alert_id, &mut transaction) {
+        Some(alert) => alert,
+        None => {
+            return ApiError::NotFound(format!("Could not find alert with id {}", alert_id)).into()
+        }
+    };
+
+    let points = TrackPoint::get_for_alert(alert.id, &mut transaction);
+    let points = match tolerance {
+        Some(tolerance) => TrackPoint::simplify(points, tolerance),
+        None => points,
+    };
+
+    StandardResponse {
+        status: Status::Ok,
+        response: json!({
+            "alertId": alert.id,
+            "tracking": alert.track_location,
+            "points": points
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = AlertCursor {
+            created_at: NaiveDateTime::parse_from_str(
+                "2024-03-01T12:30:45.123456",
+                "%Y-%m-%dT%H:%M:%S%.f",
+            )
+            .unwrap(),
+            id: 42,
+        };
+
+        let decoded = parse_cursor(&Some(cursor.encode())).unwrap().unwrap();
+        assert_eq!(decoded.created_at, cursor.created_at);
+        assert_eq!(decoded.id, cursor.id);
+        assert!(parse_cursor(&None).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_cursors_are_bad_requests() {
+        let valid = AlertCursor {
+            created_at: NaiveDateTime::from_timestamp_opt(1_700_000_000, 0).unwrap(),
+            id: 7,
+        }
+        .encode();
+
+        for cursor in &[
+            "not base64!",
+            &valid[..valid.len() - 4],
+            &base64::encode_config("2024-03-01T12:30:45", base64::URL_SAFE_NO_PAD),
+            &base64::encode_config("yesterday_7", base64::URL_SAFE_NO_PAD),
+            &base64::encode_config("2024-03-01T12:30:45_seven", base64::URL_SAFE_NO_PAD),
+        ] {
+            let err = parse_cursor(&Some(cursor.to_string())).unwrap_err();
+            assert_eq!(err.status(), Status::BadRequest, "{}", cursor);
+        }
     }
 }
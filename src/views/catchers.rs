@@ -1,8 +1,11 @@
+use crate::views::request::FailureMessage;
+use rocket::Request;
 use rocket_contrib::json::JsonValue;
 
 #[catch(500)]
 pub fn internal_error() -> JsonValue {
     json!({
+        "code": "internal_error",
         "message": "The server cannot be contacted at this moment."
     })
 }
@@ -10,20 +13,26 @@ pub fn internal_error() -> JsonValue {
 #[catch(404)]
 pub fn not_found() -> JsonValue {
     json!({
+        "code": "not_found",
         "message": "The requested resource could not be found."
     })
 }
 
 #[catch(503)]
-pub fn service_error() -> JsonValue {
-    json!({
-        "message": "The server is unavailable at this time."
-    })
+pub fn service_error(request: &Request) -> JsonValue {
+    match &request.local_cache(|| FailureMessage(None)).0 {
+        Some(message) => json!({ "code": "service_unavailable", "message": message }),
+        None => json!({
+            "code": "service_unavailable",
+            "message": "The server is unavailable at this time."
+        }),
+    }
 }
 
 #[catch(422)]
 pub fn unprocessable_entity() -> JsonValue {
     json!({
+        "code": "unprocessable_entity",
         "message": "Server could not process your request, are missing arguments?"
     })
 }
@@ -31,6 +40,7 @@ pub fn unprocessable_entity() -> JsonValue {
 #[catch(401)]
 pub fn unauthorized() -> JsonValue {
     json!({
+        "code": "unauthorized",
         "message": "Current user not authorized."
     })
 }
@@ -38,6 +48,7 @@ pub fn unauthorized() -> JsonValue {
 #[catch(400)]
 pub fn bad_request() -> JsonValue {
     json!({
+        "code": "bad_request",
         "message": "The request is missing parameters."
     })
 }
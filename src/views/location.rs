@@ -1,48 +1,50 @@
+use crate::config::Config;
+use crate::models::alerts::{Alert, TrackingPlace};
 use crate::models::auth::{BearerToken, TokenType};
 use crate::models::database::PGConnection;
+use crate::models::error::ApiError;
 use crate::models::location::Location;
+use crate::models::privacy::PrivacySettings;
 use crate::models::user::User;
 use crate::services::mapquest::{get_address, MapquestResult};
 use crate::views::request::StandardResponse;
 use crate::{fetch_user, transaction};
 use rocket::http::Status;
+use rocket::State;
 use rocket_contrib::json::Json;
 
 #[post("/location", format = "application/json", data = "<locations>")]
 pub fn update_user_location(
     locations: Json<Vec<Location>>,
     token: BearerToken,
+    config: State<Config>,
     mut connection: PGConnection,
 ) -> StandardResponse {
-    let mut locations = locations.into_inner();
+    let locations = locations.into_inner();
 
-    if locations.len() <= 0 {
-        return StandardResponse {
-            status: Status::BadRequest,
-            response: json!({
-              "message": "No new locations given"
-            }),
-        };
+    if locations.is_empty() {
+        return ApiError::validation("locations", "No new locations given").into();
     }
 
-    let mut transaction = transaction!(connection);
-    let user = fetch_user!(token.token, TokenType::Auth, &mut transaction);
-
-    // Get latest location. Unwrap safe since we checked len > 0
-    let mut location = locations.last_mut().unwrap();
-
-    location.user_id = user.id;
+    // Tracked alerts follow the upload, geocoding where to is too slow to do in its transaction
+    let (user, destination) = {
+        let mut transaction = transaction!(connection);
+        let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+        let destination = Alert::tracking_destination(&user, &locations, &config, &mut transaction);
+        (user, destination)
+    };
+    let place = destination.and_then(|location| TrackingPlace::lookup(&location, &config));
 
-    let location = match location.init_or_update(&mut transaction) {
-        Ok(location) => location,
-        Err(_) => {
-            return StandardResponse {
-                status: Status::UnprocessableEntity,
-                response: json!({
-                    "message": "Could not update user location information"
-                }),
-            }
-        }
+    let mut transaction = transaction!(connection);
+    let batch = match Location::record_batch(
+        user.id,
+        locations,
+        place.as_ref(),
+        &config,
+        &mut transaction,
+    ) {
+        Ok(batch) => batch,
+        Err(err) => return err.into(),
     };
 
     match transaction.commit() {
@@ -50,56 +52,66 @@ pub fn update_user_location(
             status: Status::Ok,
             response: json!({
                 "message": "Location updated successfully",
-                "location": location
+                "location": batch.location,
+                "recorded": batch.recorded,
+                "rejected": batch.rejected
             }),
         },
 
-        Err(_) => StandardResponse {
-            status: Status::ServiceUnavailable,
-            response: json!({
-                "message": "Unable to commit changes to database"
-            }),
-        },
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
     }
 }
 
 #[get("/location")]
-pub fn get_user_location(token: BearerToken, mut connection: PGConnection) -> StandardResponse {
+pub fn get_user_location(
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
     let mut transaction = transaction!(connection);
-    let user = fetch_user!(token.token, TokenType::Auth, &mut transaction);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    // Approximate locations stay approximate, even to the user themselves
+    let grid =
+        PrivacySettings::get_for_user(user.id, &mut transaction).location_grid(&config.locations);
 
     StandardResponse {
         status: Status::Ok,
-        response: json!(user.get_location(&mut transaction)),
+        response: json!(user
+            .get_location(&mut transaction)
+            .map(|location| location.snapped(grid))),
     }
 }
 
 #[get("/address")]
-pub fn get_user_address(token: BearerToken, mut connection: PGConnection) -> StandardResponse {
+pub fn get_user_address(
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
     let mut transaction = transaction!(connection);
-    let user = fetch_user!(token.token, TokenType::Auth, &mut transaction);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    let grid =
+        PrivacySettings::get_for_user(user.id, &mut transaction).location_grid(&config.locations);
 
     if let Some(location) = user.get_location(&mut transaction) {
-        return match get_address(location.latitude, location.longitude) {
+        let location = location.snapped(grid);
+        return match get_address(location.latitude, location.longitude, &config) {
             MapquestResult::Success(address) => StandardResponse {
                 status: Status::Ok,
                 response: json!({ "address": address }),
             },
-            MapquestResult::NoAPIKey | MapquestResult::NoValue | MapquestResult::NoResult => {
-                StandardResponse {
-                    status: Status::BadRequest,
-                    response: json!({
-                        "message": "Request to location reverse api failed"
-                    }),
-                }
+            MapquestResult::Disabled => {
+                ApiError::upstream("mapquest", "Location reverse api is disabled").into()
+            }
+            MapquestResult::RequestFailed | MapquestResult::NoValue | MapquestResult::NoResult => {
+                ApiError::upstream("mapquest", "Request to location reverse api failed").into()
             }
         };
     }
 
-    StandardResponse {
-        status: Status::BadRequest,
-        response: json!({
-            "message": "User location not found"
-        }),
-    }
+    ApiError::NotFound(String::from("User location not found")).into()
 }
@@ -5,3 +5,4 @@ pub mod location;
 pub mod pages;
 pub mod request;
 pub mod user;
+pub mod zone;
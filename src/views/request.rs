@@ -1,10 +1,14 @@
+use crate::config::Config;
 use crate::models::auth::{BasicAuth, BearerToken};
-use crate::models::database::PGConnection;
+use crate::models::database::{PGConnection, PGPool};
+use crate::models::error::ApiError;
+use crate::models::geo::DistanceUnit;
+use crate::models::session::{ClientInfo, Session};
 use rocket::http::hyper::header::Basic;
-use rocket::http::{ContentType, Status};
-use rocket::request::{self, FromRequest, Request};
+use rocket::http::{ContentType, RawStr, Status};
+use rocket::request::{self, FromFormValue, FromRequest, Request};
 use rocket::response::{self, Responder, Response};
-use rocket::Outcome;
+use rocket::{Outcome, State};
 use rocket_contrib::json::JsonValue;
 use std::str::FromStr;
 
@@ -22,6 +26,38 @@ impl<'r> Responder<'r> for StandardResponse {
     }
 }
 
+impl From<ApiError> for StandardResponse {
+    fn from(err: ApiError) -> Self {
+        let response = match &err {
+            ApiError::Validation { field, message } => json!({
+                "code": err.code(),
+                "message": message,
+                "field": field
+            }),
+            ApiError::Upstream { service, message } => json!({
+                "code": err.code(),
+                "message": message,
+                "service": service
+            }),
+            _ => json!({
+                "code": err.code(),
+                "message": err.message()
+            }),
+        };
+
+        StandardResponse {
+            status: err.status(),
+            response,
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        StandardResponse::from(self).respond_to(request)
+    }
+}
+
 pub struct HTMLResponse {
     pub status: Status,
     pub template: Option<String>,
@@ -52,6 +88,9 @@ pub fn get_health() -> StandardResponse {
     }
 }
 
+/// Message explaining why a request guard failed, picked up by the matching catcher.
+pub struct FailureMessage(pub Option<String>);
+
 #[derive(Debug)]
 pub enum FromRequestError {
     InvalidToken,
@@ -117,6 +156,13 @@ impl<'a, 'r> FromRequest<'a, 'r> for BearerToken {
                 split_token.next();
 
                 if let Some(value) = split_token.next() {
+                    if let (Outcome::Success(config), Outcome::Success(pool)) = (
+                        request.guard::<State<Config>>(),
+                        request.guard::<State<PGPool>>(),
+                    ) {
+                        Session::record_activity(value, &config, &pool);
+                    }
+
                     return Outcome::Success(BearerToken {
                         token: String::from(value),
                     });
@@ -133,11 +179,22 @@ impl<'a, 'r> FromRequest<'a, 'r> for BearerToken {
 impl<'a, 'r> FromRequest<'a, 'r> for PGConnection {
     type Error = FromRequestError;
 
-    fn from_request(_: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
-        match PGConnection::connect() {
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let pool = match request.guard::<State<PGPool>>() {
+            Outcome::Success(pool) => pool,
+            _ => {
+                error!("Database pool is not managed by rocket");
+                return Outcome::Failure((
+                    Status::ServiceUnavailable,
+                    FromRequestError::UnableToConnect,
+                ));
+            }
+        };
+
+        match PGConnection::from_pool(&pool) {
             Ok(connection) => Outcome::Success(connection),
             Err(err) => {
-                error!("{}", err);
+                request.local_cache(|| FailureMessage(Some(err)));
                 Outcome::Failure((
                     Status::ServiceUnavailable,
                     FromRequestError::UnableToConnect,
@@ -146,3 +203,28 @@ impl<'a, 'r> FromRequest<'a, 'r> for PGConnection {
         }
     }
 }
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientInfo {
+    type Error = FromRequestError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let headers = request.headers();
+
+        Outcome::Success(ClientInfo {
+            device_name: headers
+                .get_one("X-Device-Name")
+                .or_else(|| headers.get_one("User-Agent"))
+                .map(String::from),
+            platform: headers.get_one("X-Device-Platform").map(String::from),
+            ip_address: request.client_ip().map(|ip| ip.to_string()),
+        })
+    }
+}
+
+impl<'v> FromFormValue<'v> for DistanceUnit {
+    type Error = &'v RawStr;
+
+    fn from_form_value(value: &'v RawStr) -> Result<Self, Self::Error> {
+        DistanceUnit::parse(value.as_str()).ok_or(value)
+    }
+}
@@ -1,129 +1,118 @@
+use crate::config::Config;
 use crate::models::auth::*;
+use crate::models::block::BlockedUser;
 use crate::models::database::PGConnection;
+use crate::models::device::DeviceToken;
+use crate::models::error::ApiError;
+use crate::models::preferences::NotificationPreferences;
+use crate::models::privacy::PrivacySettings;
+use crate::models::session::{ClientInfo, RefreshOutcome, Session};
 use crate::models::user::User;
 use crate::services::email::send_email;
 use crate::views::request::StandardResponse;
-use crate::{fetch_user, send_email_using_file, transaction};
+use crate::{fetch_session, fetch_user, send_email_using_file, transaction};
 use bcrypt::verify;
 use rocket::http::Status;
+use rocket::State;
 use rocket_contrib::json::Json;
 use serde::{Deserialize, Serialize};
 use std::fs::read_to_string;
 
 #[post("/login")]
-pub fn login(auth: BasicAuth, mut connection: PGConnection) -> StandardResponse {
+pub fn login(
+    auth: BasicAuth,
+    client: ClientInfo,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
     let mut transaction = transaction!(connection);
 
     let user = match User::from_email(auth.username.clone(), &mut transaction) {
         Some(user) => user,
         None => {
-            return StandardResponse {
-                status: Status::BadRequest,
-                response: json!({
-                    "message": format!("No user found with email: {}", auth.username)
-                }),
-            }
+            return ApiError::NotFound(format!("No user found with email: {}", auth.username))
+                .into()
         }
     };
 
     if !user.verified {
-        return StandardResponse {
-            status: Status::PreconditionRequired,
-            response: json!({
-                "message":
-                    format!(
-                        "Please verify your account using the link we sent to: {}",
-                        user.email.as_str()
-                    )
-            }),
-        };
+        return ApiError::PreconditionRequired(format!(
+            "Please verify your account using the link we sent to: {}",
+            user.email.as_str()
+        ))
+        .into();
     }
 
     if let Some(password) = auth.password {
         match verify(password, user.password.as_str()) {
             Ok(is_correct) => {
                 if is_correct {
-                    let token = match generate_token(user.email.clone(), TokenType::Auth) {
+                    let session = match Session::init(user.id, &client, &mut transaction) {
+                        Ok(session) => session,
+                        Err(err) => return err.into(),
+                    };
+
+                    let token = match generate_access_token(user.email.clone(), session.id, &config)
+                    {
                         Ok(token) => token,
-                        Err(err) => {
-                            error!("{}", err);
-                            return StandardResponse {
-                                status: Status::UnprocessableEntity,
-                                response: json!({
-                                    "message": "Couldn't generate auth token"
-                                }),
-                            };
-                        }
+                        Err(err) => return err.into(),
                     };
 
-                    StandardResponse {
-                        status: Status::Ok,
-                        response: json!({
-                            "message": "Login successful!",
-                            "user": user,
-                            "token": token
-                        }),
+                    let refresh_token = match session.issue_refresh_token(&config, &mut transaction)
+                    {
+                        Ok(token) => token,
+                        Err(err) => return err.into(),
+                    };
+
+                    match transaction.commit() {
+                        Ok(_) => StandardResponse {
+                            status: Status::Ok,
+                            response: json!({
+                                "message": "Login successful!",
+                                "user": user,
+                                "token": token,
+                                "refreshToken": refresh_token,
+                                "expiresIn": config.auth.access_token_minutes * 60
+                            }),
+                        },
+
+                        Err(_) => ApiError::Unavailable(String::from(
+                            "Unable to commit changes to database",
+                        ))
+                        .into(),
                     }
                 } else {
-                    StandardResponse {
-                        status: Status::BadRequest,
-                        response: json!({
-                            "message": "Incorrect password"
-                        }),
-                    }
+                    ApiError::Unauthorized(String::from("Incorrect password")).into()
                 }
             }
             Err(err) => {
                 error!("{}", err);
-                StandardResponse {
-                    status: Status::UnprocessableEntity,
-                    response: json!({
-                        "message": "Couldn't compare password with hash"
-                    }),
-                }
+                ApiError::Internal(String::from("Couldn't compare password with hash")).into()
             }
         }
     } else {
-        StandardResponse {
-            status: Status::BadRequest,
-            response: json!({
-                "message": "No password was provided!"
-            }),
-        }
+        ApiError::validation("password", "No password was provided!").into()
     }
 }
 
 #[post("/", format = "application/json", data = "<user_init>")]
-pub fn create_user(user_init: Json<User>, mut connection: PGConnection) -> StandardResponse {
+pub fn create_user(
+    user_init: Json<User>,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
     let mut transaction = transaction!(connection);
 
     let new_user = match user_init.init(&mut transaction) {
         Ok(user) => user,
-        Err(_) => {
-            return StandardResponse {
-                status: Status::BadRequest,
-                response: json!({
-                    "message":
-                        format!(
-                            "User already registered with email {}",
-                            user_init.email.as_str()
-                        )
-                }),
-            }
-        }
+        Err(err) => return err.into(),
     };
 
-    let verification_token = match generate_token(new_user.email.clone(), TokenType::Verification) {
-        Ok(token) => token,
-        Err(_) => {
-            return StandardResponse {
-                status: Status::UnprocessableEntity,
-                response: json!({
-                    "message": "Could not generate email verification token"
-                }),
-            }
-        }
-    };
+    let verification_token =
+        match generate_token(new_user.email.clone(), TokenType::Verification, &config) {
+            Ok(token) => token,
+            Err(err) => return err.into(),
+        };
 
     let verification_token = match store_token(
         verification_token.clone(),
@@ -132,19 +121,13 @@ pub fn create_user(user_init: Json<User>, mut connection: PGConnection) -> Stand
         &mut transaction,
     ) {
         Ok(token) => token,
-        Err(_) => {
-            return StandardResponse {
-                status: Status::UnprocessableEntity,
-                response: json!({
-                    "message": "Could not store email verification token"
-                }),
-            }
-        }
+        Err(err) => return err.into(),
     };
 
     match transaction.commit() {
         Ok(_) => {
             send_email_using_file!(
+                &config,
                 new_user.email.as_str(),
                 "Welcome to Elevate! Please verify your email.",
                 "src/emails/welcome.html",
@@ -162,48 +145,41 @@ pub fn create_user(user_init: Json<User>, mut connection: PGConnection) -> Stand
             }
         }
 
-        Err(_) => StandardResponse {
-            status: Status::ServiceUnavailable,
-            response: json!({
-                "message": "Unable to commit changes to database"
-            }),
-        },
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
     }
 }
 
 #[post("/verify")]
-pub fn verify_email(token: BearerToken, mut connection: PGConnection) -> StandardResponse {
+pub fn verify_email(
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
     let mut transaction = transaction!(connection);
 
     let token = match retrieve_token(token.token, &mut transaction) {
         Some(token) => token,
         None => {
-            return StandardResponse {
-                status: Status::BadRequest,
-                response: json!({
-                    "message": "Token has expired or as already been used"
-                }),
-            }
+            return ApiError::Unauthorized(String::from(
+                "Token has expired or has already been used",
+            ))
+            .into()
         }
     };
 
-    let user = fetch_user!(token, TokenType::Verification, &mut transaction);
+    let user = fetch_user!(token, TokenType::Verification, &config, &mut transaction);
 
     let user = match user.verify_email(&mut transaction) {
         Ok(user) => user,
-        Err(_) => {
-            return StandardResponse {
-                status: Status::UnprocessableEntity,
-                response: json!({
-                    "message": "Could not verify user email"
-                }),
-            }
-        }
+        Err(err) => return err.into(),
     };
 
     match transaction.commit() {
         Ok(_) => {
             send_email_using_file!(
+                &config,
                 user.email.as_str(),
                 "Thanks for verifying your email!",
                 "src/emails/verification_confirmation.html",
@@ -219,40 +195,30 @@ pub fn verify_email(token: BearerToken, mut connection: PGConnection) -> Standar
             }
         }
 
-        Err(_) => StandardResponse {
-            status: Status::ServiceUnavailable,
-            response: json!({
-                "message": "Unable to commit changes to database"
-            }),
-        },
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
     }
 }
 
 #[get("/verify?<email>")]
-pub fn send_verification_email(email: String, mut connection: PGConnection) -> StandardResponse {
+pub fn send_verification_email(
+    email: String,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
     let mut transaction = transaction!(connection);
 
     let user = match User::from_email(email.clone(), &mut transaction) {
         Some(user) => user,
-        None => {
-            return StandardResponse {
-                status: Status::BadRequest,
-                response: json!({ "message": format!("No user found with email: {}", &email) }),
-            }
-        }
+        None => return ApiError::NotFound(format!("No user found with email: {}", &email)).into(),
     };
 
-    let verification_token = match generate_token(user.email.clone(), TokenType::Verification) {
-        Ok(token) => token,
-        Err(_) => {
-            return StandardResponse {
-                status: Status::UnprocessableEntity,
-                response: json!({
-                    "message": "Could not generate email verification token"
-                }),
-            }
-        }
-    };
+    let verification_token =
+        match generate_token(user.email.clone(), TokenType::Verification, &config) {
+            Ok(token) => token,
+            Err(err) => return err.into(),
+        };
 
     let verification_token = match store_token(
         verification_token.clone(),
@@ -261,19 +227,13 @@ pub fn send_verification_email(email: String, mut connection: PGConnection) -> S
         &mut transaction,
     ) {
         Ok(token) => token,
-        Err(_) => {
-            return StandardResponse {
-                status: Status::UnprocessableEntity,
-                response: json!({
-                    "message": "Could not store email verification token"
-                }),
-            }
-        }
+        Err(err) => return err.into(),
     };
 
     match transaction.commit() {
         Ok(_) => {
             send_email_using_file!(
+                &config,
                 user.email.as_str(),
                 "Please verify your email.",
                 "src/emails/verification.html",
@@ -290,47 +250,34 @@ pub fn send_verification_email(email: String, mut connection: PGConnection) -> S
             }
         }
 
-        Err(_) => StandardResponse {
-            status: Status::ServiceUnavailable,
-            response: json!({
-                "message": "Unable to commit changes to database"
-            }),
-        },
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
     }
 }
 #[get("/pwordReset?<email>")]
-pub fn request_password_reset(email: String, mut connection: PGConnection) -> StandardResponse {
+pub fn request_password_reset(
+    email: String,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
     let mut transaction = transaction!(connection);
 
     let user = match User::from_email(email.clone(), &mut transaction) {
         Some(user) => user,
-        None => {
-            return StandardResponse {
-                status: Status::BadRequest,
-                response: json!({ "message": format!("No user found with email: {}", &email) }),
-            }
-        }
+        None => return ApiError::NotFound(format!("No user found with email: {}", &email)).into(),
     };
 
     if !user.verified {
-        return StandardResponse {
-            status: Status::PreconditionRequired,
-            response: json!({
-                "message": "Your email address has not yet been verified"
-            }),
-        };
+        return ApiError::PreconditionRequired(String::from(
+            "Your email address has not yet been verified",
+        ))
+        .into();
     }
 
-    let token = match generate_token(email.clone(), TokenType::PasswordReset) {
+    let token = match generate_token(email.clone(), TokenType::PasswordReset, &config) {
         Ok(token) => token,
-        Err(_) => {
-            return StandardResponse {
-                status: Status::UnprocessableEntity,
-                response: json!({
-                    "message": "Could not generate reset token"
-                }),
-            }
-        }
+        Err(err) => return err.into(),
     };
 
     let token = match store_token(
@@ -340,19 +287,13 @@ pub fn request_password_reset(email: String, mut connection: PGConnection) -> St
         &mut transaction,
     ) {
         Ok(token) => token,
-        Err(_) => {
-            return StandardResponse {
-                status: Status::UnprocessableEntity,
-                response: json!({
-                    "message": "Could not store reset token"
-                }),
-            }
-        }
+        Err(err) => return err.into(),
     };
 
     match transaction.commit() {
         Ok(_) => {
             send_email_using_file!(
+                &config,
                 email.as_str(),
                 "Password Reset Request",
                 "src/emails/password_reset_request.html",
@@ -365,79 +306,59 @@ pub fn request_password_reset(email: String, mut connection: PGConnection) -> St
                 response: json!({ "message": format!("Reset email has been sent to {}", &email) }),
             }
         }
-        Err(_) => StandardResponse {
-            status: Status::ServiceUnavailable,
-            response: json!({
-                "message": "Unable to commit changes to database"
-            }),
-        },
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
     }
 }
 
 #[post("/pwordReset")]
-pub fn reset_password(auth: BasicAuth, mut connection: PGConnection) -> StandardResponse {
+pub fn reset_password(
+    auth: BasicAuth,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
     let mut transaction = transaction!(connection);
 
     let token = match retrieve_token(auth.username, &mut transaction) {
         Some(token) => token,
         None => {
-            return StandardResponse {
-                status: Status::BadRequest,
-                response: json!({
-                    "message": "Token has expired or has already been used"
-                }),
-            }
+            return ApiError::Unauthorized(String::from(
+                "Token has expired or has already been used",
+            ))
+            .into()
         }
     };
 
-    let email = match validate_token(token, TokenType::PasswordReset) {
-        Ok(email) => email,
+    let email = match validate_token(token, TokenType::PasswordReset, &config) {
+        Ok(claims) => claims.sub,
         Err(_) => {
-            return StandardResponse {
-                status: Status::BadRequest,
-                response: json!({
-                    "message": "Unable to verify reset token"
-                }),
-            }
+            return ApiError::Unauthorized(String::from("Unable to verify reset token")).into()
         }
     };
 
     let user = match User::from_email(email.clone(), &mut transaction) {
         Some(user) => user,
         None => {
-            return StandardResponse {
-                status: Status::BadRequest,
-                response: json!({
-                    "message": format!("Could not find user with email: {}", email)
-                }),
-            }
+            return ApiError::NotFound(format!("Could not find user with email: {}", email)).into()
         }
     };
 
-    if auth.password.is_none() {
-        return StandardResponse {
-            status: Status::BadRequest,
-            response: json!({
-                "message": "Please provide a new valid password"
-            }),
-        };
-    }
+    let password = match auth.password {
+        Some(password) => password,
+        None => {
+            return ApiError::validation("password", "Please provide a new valid password").into()
+        }
+    };
 
-    if user
-        .reset_password(auth.password.unwrap(), &mut transaction)
-        .is_err()
-    {
-        return StandardResponse {
-            status: Status::UnprocessableEntity,
-            response: json!({
-                "message": "Could not reset user's password"
-            }),
-        };
+    if let Err(err) = user.reset_password(password, &mut transaction) {
+        return err.into();
     }
 
     match transaction.commit() {
         Ok(_) => {
             send_email_using_file!(
+                &config,
                 user.email.as_str(),
                 "Your Elevate Password was Reset",
                 "src/emails/password_reset_confirmation.html",
@@ -453,54 +374,399 @@ pub fn reset_password(auth: BasicAuth, mut connection: PGConnection) -> Standard
             }
         }
 
-        Err(_) => StandardResponse {
-            status: Status::ServiceUnavailable,
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
+    }
+}
+
+#[put("/deviceTokens", format = "application/json", data = "<device_token>")]
+pub fn update_device_token(
+    device_token: Json<DeviceToken>,
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let session = fetch_session!(token.token, &config, &mut transaction);
+
+    let user = match User::from_id(session.user_id, &mut transaction) {
+        Some(user) => user,
+        None => return ApiError::NotFound(String::from("User no longer exists")).into(),
+    };
+
+    let device_token = match device_token.register(&user, &session, &mut transaction) {
+        Ok(device_token) => device_token,
+        Err(err) => return err.into(),
+    };
+
+    match transaction.commit() {
+        Ok(_) => StandardResponse {
+            status: Status::Ok,
             response: json!({
-                "message": "Unable to commit changes to database"
+                "message": "Device token updated successfully",
+                "deviceToken": device_token
             }),
         },
+
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct DeviceToken {
-    pub token: String,
+#[get("/deviceTokens")]
+pub fn get_device_tokens(
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    StandardResponse {
+        status: Status::Ok,
+        response: json!(DeviceToken::get_for_user(&user, &mut transaction)),
+    }
 }
 
-#[put("/deviceTokens", format = "application/json", data = "<device_token>")]
-pub fn update_device_token(
-    device_token: Json<DeviceToken>,
+#[delete("/deviceTokens/<device_token>")]
+pub fn delete_device_token(
+    device_token: String,
     token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    let device_token = match DeviceToken::deregister(&device_token, &user, &mut transaction) {
+        Ok(device_token) => device_token,
+        Err(err) => return err.into(),
+    };
+
+    match transaction.commit() {
+        Ok(_) => StandardResponse {
+            status: Status::Ok,
+            response: json!({
+                "message": "Device token removed successfully",
+                "deviceToken": device_token
+            }),
+        },
+
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshRequest {
+    #[serde(rename = "refreshToken")]
+    pub refresh_token: String,
+}
+
+#[post("/token/refresh", format = "application/json", data = "<refresh>")]
+pub fn refresh_token(
+    refresh: Json<RefreshRequest>,
+    client: ClientInfo,
+    config: State<Config>,
     mut connection: PGConnection,
 ) -> StandardResponse {
     let mut transaction = transaction!(connection);
-    let user = fetch_user!(token.token, TokenType::Auth, &mut transaction);
 
-    if user
-        .update_device_token(device_token.into_inner().token, &mut transaction)
-        .is_err()
+    let outcome = match Session::refresh(&refresh.refresh_token, &client, &config, &mut transaction)
     {
-        return StandardResponse {
-            status: Status::UnprocessableEntity,
+        Ok(outcome) => outcome,
+        Err(err) => return err.into(),
+    };
+
+    let response = match outcome {
+        RefreshOutcome::Rotated(session, refresh_token) => {
+            let user = match User::from_id(session.user_id, &mut transaction) {
+                Some(user) => user,
+                None => return ApiError::NotFound(String::from("User no longer exists")).into(),
+            };
+
+            let token = match generate_access_token(user.email, session.id, &config) {
+                Ok(token) => token,
+                Err(err) => return err.into(),
+            };
+
+            StandardResponse {
+                status: Status::Ok,
+                response: json!({
+                    "message": "Tokens refreshed successfully",
+                    "token": token,
+                    "refreshToken": refresh_token,
+                    "expiresIn": config.auth.access_token_minutes * 60
+                }),
+            }
+        }
+
+        // The session revocation still has to be committed before rejecting the request
+        RefreshOutcome::Reused => ApiError::Unauthorized(String::from(
+            "Refresh token was already used, this session has been revoked",
+        ))
+        .into(),
+    };
+
+    match transaction.commit() {
+        Ok(_) => response,
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
+    }
+}
+
+#[post("/logout")]
+pub fn logout(
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+
+    let session = fetch_session!(token.token, &config, &mut transaction);
+
+    if let Err(err) = session.revoke(&mut transaction) {
+        return err.into();
+    }
+
+    match transaction.commit() {
+        Ok(_) => StandardResponse {
+            status: Status::Ok,
             response: json!({
-                "message": "Device token cannot be updated at this time"
+                "message": "Logged out successfully"
             }),
-        };
+        },
+
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
+    }
+}
+
+#[post("/logout/all")]
+pub fn logout_everywhere(
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    let count = match Session::revoke_all(user.id, &mut transaction) {
+        Ok(count) => count,
+        Err(err) => return err.into(),
     };
 
     match transaction.commit() {
         Ok(_) => StandardResponse {
             status: Status::Ok,
             response: json!({
-                "message": "Device token updated successfully"
+                "message": format!("Logged out of {} session(s)", count)
             }),
         },
 
-        Err(_) => StandardResponse {
-            status: Status::ServiceUnavailable,
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
+    }
+}
+
+#[get("/sessions")]
+pub fn get_sessions(
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let current = fetch_session!(token.token, &config, &mut transaction);
+
+    let sessions = Session::get_active_for_user(current.user_id, &mut transaction)
+        .into_iter()
+        .map(|mut session| {
+            session.current = session.id == current.id;
+            session
+        })
+        .collect::<Vec<Session>>();
+
+    StandardResponse {
+        status: Status::Ok,
+        response: json!(sessions),
+    }
+}
+
+#[delete("/sessions/<session_id>")]
+pub fn revoke_session(
+    session_id: i64,
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    let session = match Session::get_active_by_id(session_id, user.id, &mut transaction) {
+        Some(session) => session,
+        None => {
+            return ApiError::NotFound(format!("Could not find session with id {}", session_id))
+                .into()
+        }
+    };
+
+    if let Err(err) = session.revoke(&mut transaction) {
+        return err.into();
+    }
+
+    match transaction.commit() {
+        Ok(_) => StandardResponse {
+            status: Status::Ok,
             response: json!({
-                "message": "Unable to commit changes to database"
+                "message": "Session revoked successfully",
+                "session": session
             }),
         },
+
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
+    }
+}
+
+#[get("/blocked")]
+pub fn get_blocked_users(
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    StandardResponse {
+        status: Status::Ok,
+        response: json!(BlockedUser::get_for_user(&user, &mut transaction)),
+    }
+}
+
+#[delete("/blocked/<blocked_user_id>")]
+pub fn unblock_user(
+    blocked_user_id: i64,
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    if let Err(err) = BlockedUser::unblock(blocked_user_id, &user, &mut transaction) {
+        return err.into();
+    }
+
+    match transaction.commit() {
+        Ok(_) => StandardResponse {
+            status: Status::Ok,
+            response: json!({
+                "message": "User unblocked successfully"
+            }),
+        },
+
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
+    }
+}
+
+#[get("/preferences")]
+pub fn get_preferences(
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    StandardResponse {
+        status: Status::Ok,
+        response: json!(NotificationPreferences::get_for_user(
+            &user,
+            &mut transaction
+        )),
+    }
+}
+
+#[put("/preferences", format = "application/json", data = "<preferences>")]
+pub fn update_preferences(
+    preferences: Json<NotificationPreferences>,
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    let preferences = match preferences.save(&user, &mut transaction) {
+        Ok(preferences) => preferences,
+        Err(err) => return err.into(),
+    };
+
+    match transaction.commit() {
+        Ok(_) => StandardResponse {
+            status: Status::Ok,
+            response: json!({
+                "message": "Notification preferences updated successfully",
+                "preferences": preferences
+            }),
+        },
+
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
+    }
+}
+
+#[get("/privacy")]
+pub fn get_privacy_settings(
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    StandardResponse {
+        status: Status::Ok,
+        response: json!(PrivacySettings::get_for_user(user.id, &mut transaction)),
+    }
+}
+
+#[put("/privacy", format = "application/json", data = "<settings>")]
+pub fn update_privacy_settings(
+    settings: Json<PrivacySettings>,
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    let settings = match settings.save(&user, &mut transaction) {
+        Ok(settings) => settings,
+        Err(err) => return err.into(),
+    };
+
+    match transaction.commit() {
+        Ok(_) => StandardResponse {
+            status: Status::Ok,
+            response: json!({
+                "message": "Privacy settings updated successfully",
+                "privacy": settings
+            }),
+        },
+
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
     }
 }
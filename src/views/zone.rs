@@ -0,0 +1,130 @@
+use crate::config::Config;
+use crate::models::auth::{BearerToken, TokenType};
+use crate::models::database::PGConnection;
+use crate::models::error::ApiError;
+use crate::models::user::User;
+use crate::models::zone::WatchZone;
+use crate::views::request::StandardResponse;
+use crate::{fetch_user, transaction};
+use rocket::http::Status;
+use rocket::State;
+use rocket_contrib::json::Json;
+
+#[get("/zones")]
+pub fn get_zones(
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    StandardResponse {
+        status: Status::Ok,
+        response: json!({ "zones": WatchZone::get_for_user(&user, &mut transaction) }),
+    }
+}
+
+#[post("/zones", format = "application/json", data = "<zone>")]
+pub fn create_zone(
+    zone: Json<WatchZone>,
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    let zone = match zone.init(&user, &mut transaction) {
+        Ok(zone) => zone,
+        Err(err) => return err.into(),
+    };
+
+    match transaction.commit() {
+        Ok(_) => StandardResponse {
+            status: Status::Created,
+            response: json!({
+                "message": "Zone created successfully",
+                "zone": zone
+            }),
+        },
+
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
+    }
+}
+
+#[put("/zones/<zone_id>", format = "application/json", data = "<updated>")]
+pub fn update_zone(
+    zone_id: i64,
+    updated: Json<WatchZone>,
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    let zone = match WatchZone::get_by_id(zone_id, &user, &mut transaction) {
+        Some(zone) => zone,
+        None => {
+            return ApiError::NotFound(format!("Could not find zone with id {}", zone_id)).into()
+        }
+    };
+
+    let zone = match zone.update(&updated, &mut transaction) {
+        Ok(zone) => zone,
+        Err(err) => return err.into(),
+    };
+
+    match transaction.commit() {
+        Ok(_) => StandardResponse {
+            status: Status::Ok,
+            response: json!({
+                "message": "Zone updated successfully",
+                "zone": zone
+            }),
+        },
+
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
+    }
+}
+
+#[delete("/zones/<zone_id>")]
+pub fn delete_zone(
+    zone_id: i64,
+    token: BearerToken,
+    config: State<Config>,
+    mut connection: PGConnection,
+) -> StandardResponse {
+    let mut transaction = transaction!(connection);
+    let user = fetch_user!(token.token, TokenType::Auth, &config, &mut transaction);
+
+    let zone = match WatchZone::get_by_id(zone_id, &user, &mut transaction) {
+        Some(zone) => zone,
+        None => {
+            return ApiError::NotFound(format!("Could not find zone with id {}", zone_id)).into()
+        }
+    };
+
+    if let Err(err) = zone.delete(&mut transaction) {
+        return err.into();
+    }
+
+    match transaction.commit() {
+        Ok(_) => StandardResponse {
+            status: Status::Ok,
+            response: json!({
+                "message": "Zone deleted successfully",
+                "zone": zone
+            }),
+        },
+
+        Err(_) => {
+            ApiError::Unavailable(String::from("Unable to commit changes to database")).into()
+        }
+    }
+}